@@ -0,0 +1,95 @@
+/**
+* Module     : icrc2.rs
+* Copyright  : 2022 Fleek
+* License    : GPL 3.0
+* Maintainer : Psychedelic <support@fleek.co>
+* Stability  : Experimental
+*/
+use candid::{CandidType, Deserialize, Nat};
+use ic_kit::Principal;
+use sha2::{Digest, Sha224};
+use std::convert::TryFrom;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Account {
+  pub owner: Principal,
+  pub subaccount: Option<Vec<u8>>,
+}
+
+// `created_at_time` is accepted for compatibility, approvals are not
+// deduplicated.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveArgs {
+  pub from_subaccount: Option<Vec<u8>>,
+  pub spender: Account,
+  pub amount: Nat,
+  pub expected_allowance: Option<Nat>,
+  pub expires_at: Option<u64>,
+  pub fee: Option<Nat>,
+  pub memo: Option<Vec<u8>>,
+  pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveError {
+  BadFee { expected_fee: Nat },
+  InsufficientFunds { balance: Nat },
+  AllowanceChanged { current_allowance: Nat },
+  Expired { ledger_time: u64 },
+  TooOld,
+  CreatedInFuture { ledger_time: u64 },
+  Duplicate { duplicate_of: Nat },
+  TemporarilyUnavailable,
+  GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+  pub account: Account,
+  pub spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Allowance {
+  pub allowance: Nat,
+  pub expires_at: Option<u64>,
+}
+
+// Principal an ICRC account maps onto, see `derive_principal`. `None` if
+// the subaccount isn't 32 bytes.
+pub fn account_principal(
+  canister: Principal,
+  owner: Principal,
+  subaccount: &Option<Vec<u8>>,
+) -> Option<Principal> {
+  match subaccount {
+    Some(bytes) => {
+      let subaccount = <[u8; 32]>::try_from(bytes.as_slice()).ok()?;
+      Some(derive_principal(canister, owner, &subaccount))
+    }
+    None => Some(owner),
+  }
+}
+
+// Principal standing for `owner`'s `subaccount` on the token `canister`, a
+// derived id (class 0x03) nobody holds a key for:
+//
+//   SHA-224(len(canister) | canister | len(owner) | owner | subaccount) | 0x03
+//
+// where `len` is one byte and `subaccount` the 32 subaccount bytes. The
+// default subaccount is `owner` itself. Including the canister keeps the
+// principals of two tokens apart.
+pub fn derive_principal(canister: Principal, owner: Principal, subaccount: &[u8; 32]) -> Principal {
+  if subaccount.iter().all(|byte| *byte == 0) {
+    return owner;
+  }
+  let mut hasher = Sha224::new();
+  for id in [canister, owner] {
+    hasher.update([id.as_slice().len() as u8]);
+    hasher.update(id.as_slice());
+  }
+  hasher.update(subaccount);
+  let mut bytes = hasher.finalize().to_vec();
+  bytes.push(0x03);
+  Principal::from_slice(&bytes)
+}
@@ -0,0 +1,201 @@
+/**
+* Module     : icrc3.rs
+* Copyright  : 2022 Fleek
+* License    : GPL 3.0
+* Maintainer : Psychedelic <support@fleek.co>
+* Stability  : Experimental
+*/
+use candid::{CandidType, Deserialize, Nat};
+use cap_std::dip20::{Operation, TxRecord};
+use ic_certified_map::{fork, labeled, leaf, HashTree};
+use ic_kit::{ic, Principal};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// ICRC-3 generic value. `Int` is left out, no block uses it.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Value {
+  Blob(Vec<u8>),
+  Text(String),
+  Nat(Nat),
+  Array(Vec<Value>),
+  Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+  pub start: Nat,
+  pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+  pub id: Nat,
+  pub block: Value,
+}
+
+// Only blocks still held in the local history are served, there is no
+// archive canister so `archived_blocks` is not part of the result.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+  pub log_length: Nat,
+  pub blocks: Vec<BlockWithId>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetArchivesArgs {
+  pub from: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchiveInfo {
+  pub canister_id: Principal,
+  pub start: Nat,
+  pub end: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DataCertificate {
+  pub certificate: Vec<u8>,
+  pub hash_tree: Vec<u8>,
+}
+
+// Encodes a record as an ICRC-3 block chained to the block hashed `phash`,
+// which is only absent for the first block.
+pub fn block(record: &TxRecord, phash: Option<Vec<u8>>) -> Value {
+  let (btype, mut tx) = match record.operation {
+    Operation::Mint => ("1mint", vec![("to", account(record.to))]),
+    Operation::Burn => ("1burn", vec![("from", account(record.from))]),
+    Operation::Transfer => (
+      "1xfer",
+      vec![("from", account(record.from)), ("to", account(record.to))],
+    ),
+    Operation::TransferFrom => (
+      "2xfer",
+      vec![
+        ("from", account(record.from)),
+        ("to", account(record.to)),
+        ("spender", account(record.caller.unwrap_or(record.from))),
+      ],
+    ),
+    Operation::Approve => (
+      "2approve",
+      vec![
+        ("from", account(record.from)),
+        ("spender", account(record.to)),
+      ],
+    ),
+  };
+  tx.push(("amt", Value::Nat(record.amount.clone())));
+  let mut fields = vec![
+    ("btype", Value::Text(btype.to_string())),
+    (
+      "ts",
+      Value::Nat(Nat(record.timestamp.0.to_biguint().unwrap_or_default())),
+    ),
+    ("tx", map(tx)),
+  ];
+  if record.fee != 0 {
+    fields.push(("fee", Value::Nat(record.fee.clone())));
+  }
+  if let Some(phash) = phash {
+    fields.push(("phash", Value::Blob(phash)));
+  }
+  map(fields)
+}
+
+// Representation independent hash of a value, as specified by ICRC-3.
+pub fn hash(value: &Value) -> Vec<u8> {
+  let mut hasher = Sha256::new();
+  match value {
+    Value::Blob(bytes) => hasher.update(bytes),
+    Value::Text(text) => hasher.update(text.as_bytes()),
+    Value::Nat(nat) => hasher.update(leb128(nat)),
+    Value::Array(values) => {
+      for value in values {
+        hasher.update(hash(value));
+      }
+    }
+    Value::Map(fields) => {
+      let mut entries: Vec<Vec<u8>> = fields
+        .iter()
+        .map(|(key, value)| {
+          let mut entry = Sha256::digest(key.as_bytes()).to_vec();
+          entry.extend(hash(value));
+          entry
+        })
+        .collect();
+      entries.sort();
+      for entry in entries {
+        hasher.update(entry);
+      }
+    }
+  }
+  hasher.finalize().to_vec()
+}
+
+// Sets the canister's certified data to the root of the tip tree. Has to
+// run in an update call, init or post_upgrade.
+pub fn certify(last_index: usize, tip_hash: &[u8]) {
+  let index = leb128(&Nat::from(last_index));
+  ic::set_certified_data(&tip_tree(&index, tip_hash).reconstruct());
+}
+
+// The certificate over the tip tree, only available in query calls.
+pub fn tip_certificate(last_index: usize, tip_hash: &[u8]) -> Option<DataCertificate> {
+  let certificate = ic::data_certificate()?;
+  let index = leb128(&Nat::from(last_index));
+  let mut serializer = serde_cbor::Serializer::new(Vec::new());
+  serializer.self_describe().ok()?;
+  tip_tree(&index, tip_hash).serialize(&mut serializer).ok()?;
+  Some(DataCertificate {
+    certificate,
+    hash_tree: serializer.into_inner(),
+  })
+}
+
+fn tip_tree<'a>(index: &'a [u8], tip_hash: &'a [u8]) -> HashTree<'a> {
+  fork(
+    labeled(b"last_block_hash", leaf(tip_hash)),
+    labeled(b"last_block_index", leaf(index)),
+  )
+}
+
+fn account(owner: Principal) -> Value {
+  Value::Array(vec![Value::Blob(owner.as_slice().to_vec())])
+}
+
+fn map(fields: Vec<(&str, Value)>) -> Value {
+  Value::Map(
+    fields
+      .into_iter()
+      .map(|(key, value)| (key.to_string(), value))
+      .collect(),
+  )
+}
+
+fn leb128(nat: &Nat) -> Vec<u8> {
+  let mut groups = Vec::new();
+  let mut acc: u32 = 0;
+  let mut bits = 0;
+  for byte in nat.0.to_bytes_le() {
+    acc |= (byte as u32) << bits;
+    bits += 8;
+    while bits >= 7 {
+      groups.push((acc & 0x7f) as u8);
+      acc >>= 7;
+      bits -= 7;
+    }
+  }
+  if bits > 0 {
+    groups.push(acc as u8);
+  }
+  while groups.len() > 1 && groups.last() == Some(&0) {
+    groups.pop();
+  }
+  let last = groups.len() - 1;
+  for group in &mut groups[..last] {
+    *group |= 0x80;
+  }
+  groups
+}
@@ -7,23 +7,184 @@
 */
 use candid::{candid_method, CandidType, Deserialize, Int, Nat};
 use cap_sdk::{archive, from_archive, Archive};
-use cap_sdk::{handshake, insert, Event, IndefiniteEvent, TypedEvent};
+use cap_sdk::{handshake, insert, DetailValue, Event, IndefiniteEvent, TypedEvent};
 use cap_std::dip20::cap::DIP20Details;
 use cap_std::dip20::{Operation, TransactionStatus, TxRecord};
+use ic_cdk::api::stable::{StableReader, StableWriter};
 use ic_cdk_macros::*;
 use ic_kit::{ic, Principal};
-use std::cell::RefCell;
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::convert::Into;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io::{Read, Write};
 use std::iter::FromIterator;
 use std::string::String;
 
+mod icrc2;
+mod icrc3;
+mod signed;
+mod stable;
+
+use icrc2::{Allowance, AllowanceArgs, ApproveArgs, ApproveError};
+use icrc3::{
+  ArchiveInfo, BlockWithId, DataCertificate, GetArchivesArgs, GetBlocksArgs, GetBlocksResult,
+};
+
 #[derive(CandidType, Default, Deserialize, Clone)]
 pub struct TxLog {
   pub ie_records: VecDeque<IndefiniteEvent>,
 }
 
+// Local copy of the most recent `MAX_HISTORY_CACHE` records sent to CAP, so
+// lookups don't have to go through CAP. `evicted` is the number of records
+// dropped from the front, i.e. the index of `records[0]`. `user_index` maps a
+// principal to the indexes of the cached records where it appears as the
+// caller, `from` or `to`. Records also form an ICRC-3 block chain:
+// `phashes[i]` is the parent hash `records[i]` was encoded with and
+// `tip_hash` the hash of the latest block. `op_index` holds the indexes of
+// the cached records per operation, see `op_key`. It is optional so history
+// saved before it existed still decodes, `index_operations` rebuilds it.
+#[derive(CandidType, Default, Deserialize, Clone)]
+pub struct TxHistory {
+  pub records: VecDeque<TxRecord>,
+  pub evicted: usize,
+  pub user_index: HashMap<Principal, Vec<usize>>,
+  pub memos: HashMap<usize, Vec<u8>>,
+  pub phashes: VecDeque<Option<Vec<u8>>>,
+  pub tip_hash: Option<Vec<u8>>,
+  pub content_index: HashMap<Vec<u8>, usize>,
+  pub op_index: Option<HashMap<u8, VecDeque<usize>>>,
+}
+
+impl TxHistory {
+  fn get(&self, index: usize) -> Option<&TxRecord> {
+    index
+      .checked_sub(self.evicted)
+      .and_then(|position| self.records.get(position))
+  }
+
+  fn block(&self, index: usize) -> Option<icrc3::Value> {
+    let position = index.checked_sub(self.evicted)?;
+    let record = self.records.get(position)?;
+    Some(icrc3::block(record, self.phashes[position].clone()))
+  }
+
+  fn push(&mut self, record: TxRecord) {
+    let index = self.evicted + self.records.len();
+    for user in Self::users(&record) {
+      self.user_index.entry(user).or_default().push(index);
+    }
+    let phash = self.tip_hash.take();
+    self.tip_hash = Some(icrc3::hash(&icrc3::block(&record, phash.clone())));
+    self.phashes.push_back(phash);
+    self
+      .content_index
+      .entry(Self::content_hash(&record))
+      .or_insert(index);
+    self
+      .op_index
+      .get_or_insert_with(HashMap::new)
+      .entry(Self::op_key(&record.operation))
+      .or_default()
+      .push_back(index);
+    self.records.push_back(record);
+    if self.records.len() > MAX_HISTORY_CACHE {
+      self.evict();
+    }
+  }
+
+  fn evict(&mut self) {
+    let record = match self.records.pop_front() {
+      Some(record) => record,
+      None => return,
+    };
+    self.phashes.pop_front();
+    let evicted = self.evicted;
+    for user in Self::users(&record) {
+      if let Some(indexes) = self.user_index.get_mut(&user) {
+        indexes.retain(|index| *index != evicted);
+        if indexes.is_empty() {
+          self.user_index.remove(&user);
+        }
+      }
+    }
+    self.memos.remove(&evicted);
+    let hash = Self::content_hash(&record);
+    if self.content_index.get(&hash) == Some(&evicted) {
+      self.content_index.remove(&hash);
+    }
+    if let Some(op_index) = &mut self.op_index {
+      if let Some(indexes) = op_index.get_mut(&Self::op_key(&record.operation)) {
+        indexes.pop_front();
+      }
+    }
+    self.evicted += 1;
+  }
+
+  // SHA-256 over `from`, `to`, `amount` and `timestamp`, in that order. Each
+  // field is a one byte length followed by its bytes: the raw principal
+  // bytes, and the amount and timestamp (nanoseconds) as minimal unsigned
+  // big-endian integers, zero being a single 0x00 byte.
+  fn content_hash(record: &TxRecord) -> Vec<u8> {
+    let timestamp = record.timestamp.0.to_biguint().unwrap_or_default();
+    let mut hasher = Sha256::new();
+    for field in [
+      record.from.as_slice().to_vec(),
+      record.to.as_slice().to_vec(),
+      record.amount.0.to_bytes_be(),
+      timestamp.to_bytes_be(),
+    ] {
+      hasher.update([field.len() as u8]);
+      hasher.update(field);
+    }
+    hasher.finalize().to_vec()
+  }
+
+  fn index_operations(&mut self) {
+    let mut op_index: HashMap<u8, VecDeque<usize>> = HashMap::new();
+    for (position, record) in self.records.iter().enumerate() {
+      op_index
+        .entry(Self::op_key(&record.operation))
+        .or_default()
+        .push_back(self.evicted + position);
+    }
+    self.op_index = Some(op_index);
+  }
+
+  fn op_key(op: &Operation) -> u8 {
+    match op {
+      Operation::Approve => 0,
+      Operation::Mint => 1,
+      Operation::Transfer => 2,
+      Operation::TransferFrom => 3,
+      Operation::Burn => 4,
+    }
+  }
+
+  fn users(record: &TxRecord) -> Vec<Principal> {
+    let mut users = vec![record.from, record.to];
+    if let Some(caller) = record.caller {
+      users.push(caller);
+    }
+    users.sort();
+    users.dedup();
+    users
+  }
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct HistoryCacheInfo {
+  cached_count: usize,
+  oldest_index: Nat,
+  complete: bool,
+}
+
 #[allow(non_snake_case)]
 #[derive(Deserialize, CandidType, Clone, Debug)]
 struct Metadata {
@@ -36,6 +197,8 @@ struct Metadata {
   fee: Nat,
 }
 
+// Fields added after the stable layout was versioned are optional so stats
+// saved before them still decode, `None` stands for the default.
 #[derive(Deserialize, CandidType, Clone, Debug)]
 struct StatsData {
   logo: String,
@@ -48,6 +211,38 @@ struct StatsData {
   fee_to: Principal,
   history_size: usize,
   deploy_time: u64,
+  burn_fees: bool,
+  tx_counter: usize,
+  sale_start: Option<u64>,
+  sale_end: Option<u64>,
+  fee_token: Option<Principal>,
+  fee_token_amount: Nat,
+  require_cap_success: bool,
+  min_approval: Nat,
+  rate_limit: Option<(usize, u64)>,
+  repair_on_upgrade: bool,
+  cap: Option<Principal>,
+  pending_owner: Option<Principal>,
+  emission_rate: Nat,
+  last_mint_time: u64,
+  min_cycles_threshold: u64,
+  mint_count: usize,
+  burn_count: usize,
+  transfer_count: usize,
+  approve_count: usize,
+  recovery_enabled: bool,
+  transfer_cooldown_secs: u64,
+  max_logo_size: usize,
+  snapshot_counter: u64,
+  max_snapshots: usize,
+  upgrade_count: u64,
+  last_upgrade_time: u64,
+  max_supply_points: Option<usize>,
+  reject_anonymous: Option<bool>,
+  max_holders: Option<usize>,
+  cap_batch_size: Option<usize>,
+  max_representable_supply: Option<Nat>,
+  allow_zero_transfers: Option<bool>,
 }
 
 #[allow(non_snake_case)]
@@ -59,9 +254,105 @@ struct TokenInfo {
   historySize: usize,
   deployTime: u64,
   holderNumber: usize,
+  blockedNumber: usize,
   cycles: u64,
 }
 
+// `Metadata` plus the fee and holder settings, a separate type so
+// `getMetadata` keeps its shape. There is no pause or minimum transfer
+// amount in this token, so those aren't part of it.
+#[allow(non_snake_case)]
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct ExtendedMetadata {
+  metadata: Metadata,
+  feeDestination: FeeDestination,
+  feeToken: Option<Principal>,
+  feeTokenAmount: Nat,
+  minApproval: Nat,
+  maxHolders: Option<usize>,
+  maxRepresentableSupply: Nat,
+}
+
+// A fee collected in the fee token by `_collect_token_fee`.
+#[derive(Clone, Debug)]
+struct TokenFee {
+  fee_token: Principal,
+  payer: Principal,
+  fee_to: Principal,
+  amount: Nat,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct SpendableNow {
+  amount: Nat,
+  reason_if_zero: Option<String>,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct TransferPreview {
+  fee: Nat,
+  total_debit: Nat,
+  sender_balance_after: Nat,
+  recipient_balance_after: Nat,
+}
+
+// Where charged fees go, a typed view of `fee_to` and `burn_fees`.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+enum FeeDestination {
+  Account(Principal),
+  Burn,
+}
+
+// External canisters the token depends on. There is no ICP ledger, the
+// token never calls one. `cap` is unknown for tokens deployed before it was
+// recorded.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct Wiring {
+  canister: Principal,
+  cap: Option<Principal>,
+  fee_token: Option<Principal>,
+}
+
+// Records per operation since the counters were added, older records aren't
+// counted. `transfer` includes `transferFrom`.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct OperationCounts {
+  mint: usize,
+  burn: usize,
+  transfer: usize,
+  approve: usize,
+}
+
+// `data_version` is the stable layout the running code writes, see
+// `DATA_VERSION`.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct UpgradeInfo {
+  upgrade_count: u64,
+  last_upgrade_time: u64,
+  data_version: u32,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct TransactionsPage {
+  records: Vec<TxRecord>,
+  more: bool,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct TransferResult {
+  tx_id: Nat,
+  fee_charged: Nat,
+  new_sender_balance: Nat,
+  timestamp: u64,
+}
+
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct AllowanceEdge {
+  owner: Principal,
+  spender: Principal,
+  value: Nat,
+}
+
 impl Default for StatsData {
   fn default() -> Self {
     StatsData {
@@ -75,14 +366,168 @@ impl Default for StatsData {
       fee_to: Principal::anonymous(),
       history_size: 0,
       deploy_time: 0,
+      burn_fees: false,
+      tx_counter: 0,
+      sale_start: None,
+      sale_end: None,
+      fee_token: None,
+      fee_token_amount: Nat::from(0),
+      require_cap_success: false,
+      min_approval: Nat::from(0),
+      rate_limit: None,
+      repair_on_upgrade: false,
+      cap: None,
+      pending_owner: None,
+      emission_rate: Nat::from(0),
+      last_mint_time: 0,
+      min_cycles_threshold: 0,
+      mint_count: 0,
+      burn_count: 0,
+      transfer_count: 0,
+      approve_count: 0,
+      recovery_enabled: true,
+      transfer_cooldown_secs: 0,
+      max_logo_size: DEFAULT_MAX_LOGO_SIZE,
+      snapshot_counter: 0,
+      max_snapshots: DEFAULT_MAX_SNAPSHOTS,
+      upgrade_count: 0,
+      last_upgrade_time: 0,
+      max_supply_points: None,
+      reject_anonymous: None,
+      max_holders: None,
+      cap_batch_size: None,
+      max_representable_supply: None,
+      allow_zero_transfers: None,
     }
   }
 }
 
 type Balances = HashMap<Principal, Nat>;
 type Allowances = HashMap<Principal, HashMap<Principal, Nat>>;
+type Expiries = HashMap<(Principal, Principal), u64>;
+type Nonces = HashMap<Principal, VecDeque<(u64, Nat)>>;
+type Spenders = HashMap<Principal, HashSet<Principal>>;
+type SignedNonces = HashMap<Principal, u64>;
+// Snapshots for `balanceOfAt` and `totalSupplyAt`. `supplies` holds the
+// total supply of every kept snapshot by id. Balances are kept lazily: the
+// first change to an account after a snapshot stores the balance it had
+// under the id of the latest snapshot. A snapshot without an entry for an
+// account reads the next later entry, or the current balance if there is
+// none, as the balance didn't change in between.
+#[derive(CandidType, Default, Deserialize, Clone)]
+struct Snapshots {
+  supplies: BTreeMap<u64, Nat>,
+  balances: HashMap<Principal, BTreeMap<u64, Nat>>,
+}
+
+impl Snapshots {
+  // Keeps `balance`, what `account` holds right before it changes, for the
+  // latest snapshot unless it's kept already.
+  fn record(&mut self, account: Principal, balance: impl FnOnce() -> Nat) {
+    if let Some(latest) = self.supplies.keys().next_back() {
+      self
+        .balances
+        .entry(account)
+        .or_default()
+        .entry(*latest)
+        .or_insert_with(balance);
+    }
+  }
+
+  fn balance_at(&self, snapshot_id: u64, account: Principal, current: Nat) -> Option<Nat> {
+    self.supplies.get(&snapshot_id)?;
+    let kept = self
+      .balances
+      .get(&account)
+      .and_then(|kept| kept.range(snapshot_id..).next());
+    Some(kept.map_or(current, |(_, balance)| balance.clone()))
+  }
+
+  fn evict(&mut self, max_snapshots: usize) {
+    while self.supplies.len() > max_snapshots {
+      let oldest = *self.supplies.keys().next().unwrap();
+      self.supplies.remove(&oldest);
+      self.balances.retain(|_, kept| {
+        kept.remove(&oldest);
+        !kept.is_empty()
+      });
+    }
+  }
+}
+
+// Total supply and balances by snapshot id, the full copies snapshots used
+// to be, see `_migrate_snapshots`.
+type SnapshotCopies = BTreeMap<u64, (Nat, Balances)>;
+// `(timestamp, total supply)` after each supply change, oldest first.
+type SupplyHistory = VecDeque<(u64, Nat)>;
+// `(approved, spent)` per `(owner, spender)` since the last approval.
+type Drawdowns = HashMap<(Principal, Principal), (Nat, Nat)>;
+
+// State only the chunked layout stores. Restoring from the unchunked one
+// starts from the defaults and rebuilds the spender index. Fields added
+// after version 2 are optional so extras saved by it still decode.
+// `snapshots` holds the copies saved before `snapshot_diffs` existed, it is
+// migrated on restore and saved empty since.
+#[derive(CandidType, Default, Deserialize)]
+struct StableExtras {
+  spenders: Option<Spenders>,
+  signed_nonces: SignedNonces,
+  last_transfer: HashMap<Principal, u64>,
+  snapshots: SnapshotCopies,
+  supply_history: Option<SupplyHistory>,
+  burn_sinks: Option<HashSet<Principal>>,
+  drawdowns: Option<Drawdowns>,
+  fee_exempt: Option<HashSet<Principal>>,
+  cap_queue: Option<VecDeque<IndefiniteEvent>>,
+  snapshot_diffs: Option<Snapshots>,
+}
+// The unchunked layout written by `ic::stable_store` before the layout was
+// versioned. `StatsDataV0` is `StatsData` as it was then and must not
+// change, `_migrate_v0` turns it into the current one.
+#[derive(Deserialize, CandidType, Clone, Debug)]
+struct StatsDataV0 {
+  logo: String,
+  name: String,
+  symbol: String,
+  decimals: u8,
+  total_supply: Nat,
+  owner: Principal,
+  fee: Nat,
+  fee_to: Principal,
+  history_size: usize,
+  deploy_time: u64,
+}
+type StableStateV0 = (StatsDataV0, Balances, Allowances, TxLog, Archive);
+type StableState = (
+  StatsData,
+  Balances,
+  Allowances,
+  TxLog,
+  Archive,
+  TxHistory,
+  HashSet<Principal>,
+  HashMap<Principal, Nat>,
+  Expiries,
+  Nonces,
+  HashSet<Principal>,
+  HashMap<Principal, String>,
+);
+// What the chunked layout stores next to the balance and allowance chunks.
+type StableValues = (
+  StatsData,
+  TxLog,
+  Archive,
+  TxHistory,
+  HashSet<Principal>,
+  HashMap<Principal, Nat>,
+  Expiries,
+  Nonces,
+  HashSet<Principal>,
+  HashMap<Principal, String>,
+  StableExtras,
+);
 
-#[derive(CandidType, Debug, PartialEq)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub enum TxError {
   InsufficientBalance,
   InsufficientAllowance,
@@ -93,18 +538,61 @@ pub enum TxError {
   ErrorOperationStyle,
   ErrorTo,
   Other(String),
+  AccountBlocked,
+  LowCycles,
+  Cooldown,
+  AllowanceChanged,
+  MaxHoldersReached,
+  SupplyOverflow,
 }
 pub type TxReceipt = Result<Nat, TxError>;
 
+// Stable layout version `pre_upgrade` writes, see `stable::version`. Bump it
+// and add a migration to `post_upgrade` when the saved state changes shape.
+const DATA_VERSION: u32 = 2;
+const MAX_MEMO_SIZE: usize = 32;
+const MAX_HISTORY_CACHE: usize = 100_000;
+const MAX_METHOD_NAME_SIZE: usize = 64;
+const MAX_NONCES_PER_PRINCIPAL: usize = 100;
+const MAX_LISTENERS: usize = 16;
+const MAX_BATCH_SIZE: usize = 10_000;
+const DEFAULT_MAX_LOGO_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_SNAPSHOTS: usize = 10;
+const DEFAULT_MAX_SUPPLY_POINTS: usize = 1_000;
+const CAP_FLUSH_INTERVAL_SECS: u64 = 60;
+const CAP_FLUSH_STALE_SECS: u64 = 10 * 60;
+const MAX_BLOCKS_PER_RESPONSE: usize = 1_000;
+
 thread_local! {
     static BALANCES: RefCell<HashMap<Principal, Nat>> = RefCell::new(HashMap::default());
     static ALLOWS: RefCell<HashMap<Principal, HashMap<Principal, Nat>>> = RefCell::new(HashMap::default());
     static STATS: RefCell<StatsData> = RefCell::new(StatsData::default());
     static TXLOG: RefCell<TxLog> = RefCell::new(TxLog::default());
+    static HISTORY: RefCell<TxHistory> = RefCell::new(TxHistory::default());
+    static RECORDING: RefCell<HashMap<Principal, usize>> = RefCell::new(HashMap::default());
+    static BLOCKLIST: RefCell<HashSet<Principal>> = RefCell::new(HashSet::default());
+    static MINTERS: RefCell<HashMap<Principal, Nat>> = RefCell::new(HashMap::default());
+    static EXPIRIES: RefCell<HashMap<(Principal, Principal), u64>> = RefCell::new(HashMap::default());
+    static NONCES: RefCell<HashMap<Principal, VecDeque<(u64, Nat)>>> = RefCell::new(HashMap::default());
+    static EXCLUDED: RefCell<HashSet<Principal>> = RefCell::new(HashSet::default());
+    static SPENDERS: RefCell<HashMap<Principal, HashSet<Principal>>> = RefCell::new(HashMap::default());
+    static SIGNED_NONCES: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::default());
+    static LAST_TRANSFER: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::default());
+    static LISTENERS: RefCell<HashMap<Principal, String>> = RefCell::new(HashMap::default());
+    static SNAPSHOTS: RefCell<Snapshots> = RefCell::new(Snapshots::default());
+    static SUPPLY_HISTORY: RefCell<SupplyHistory> = RefCell::new(VecDeque::default());
+    static BURN_SINKS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::default());
+    static DRAWDOWNS: RefCell<Drawdowns> = RefCell::new(HashMap::default());
+    static FEE_EXEMPT: RefCell<HashSet<Principal>> = RefCell::new(HashSet::default());
+    static TRANSFER_TIMES: RefCell<HashMap<Principal, VecDeque<u64>>> = RefCell::new(HashMap::default());
+    static CAP_QUEUE: RefCell<VecDeque<IndefiniteEvent>> = RefCell::new(VecDeque::default());
+    static CAP_FLUSHING: Cell<bool> = const { Cell::new(false) };
+    static CAP_LAST_FLUSH: Cell<u64> = const { Cell::new(0) };
 }
 
 #[init]
 #[candid_method(init)]
+#[allow(clippy::too_many_arguments)]
 fn init(
   logo: String,
   name: String,
@@ -115,7 +603,17 @@ fn init(
   fee: Nat,
   fee_to: Principal,
   cap: Principal,
+  sale_start: Option<u64>,
+  sale_end: Option<u64>,
 ) {
+  assert!(decimals <= 18, "decimals must be at most 18");
+  assert!(!name.is_empty(), "name must not be empty");
+  assert!(!symbol.is_empty(), "symbol must not be empty");
+  assert!(
+    fee == 0 || fee_to != Principal::anonymous(),
+    "fee_to must not be anonymous when there is a fee"
+  );
+  _check_logo_size(&logo);
   STATS.with(|s| {
     let mut stats = s.borrow_mut();
     stats.logo = logo;
@@ -128,12 +626,16 @@ fn init(
     stats.fee_to = fee_to;
     stats.history_size = 1;
     stats.deploy_time = ic::time();
+    stats.sale_start = sale_start;
+    stats.sale_end = sale_end;
+    stats.cap = Some(cap);
   });
+  _supply_point();
   handshake(1_000_000_000_000, Some(cap));
-  BALANCES.with(|b| {
-    b.borrow_mut().insert(owner, total_supply.clone());
-  });
-  let _ = add_record(
+  _balance_ins(owner, total_supply.clone());
+  // the CAP insert of the genesis mint can't be awaited in init, the next
+  // insert sends it
+  _add_record_detached(
     owner,
     Operation::Mint,
     owner,
@@ -147,160 +649,451 @@ fn init(
 
 /* UPDATE FNS */
 
+// Update methods apply all of their state changes inside `_mutate` and only
+// then await the CAP insert returned by `add_record`. The IC can run other
+// messages at that await, so nothing may be read or written after it: the
+// closure given to `_mutate` is synchronous, which keeps every check and its
+// mutation in the same message execution.
+
 #[update]
 #[candid_method(update)]
 async fn transfer(to: Principal, value: Nat) -> TxReceipt {
   let from = ic::caller();
-  let fee = _get_fee();
-  if balance_of(from) < value.clone() + fee.clone() {
-    return Err(TxError::InsufficientBalance);
+  let token_fee = _collect_token_fee(from, to, value.clone()).await?;
+  if !_require_cap_success() {
+    let insert = _refund_on_error(token_fee, _mutate(|| _send(from, to, value))).await?;
+    return insert.await;
   }
-  _charge_fee(from, fee.clone());
-  _transfer(from, to, value.clone());
-  _history_inc();
-  add_record(
-    from,
-    Operation::Transfer,
-    from,
-    to,
-    value,
-    fee,
-    ic::time(),
-    TransactionStatus::Succeeded,
-  )
-  .await
+  // The record has to make it into CAP, otherwise the transfer is rolled
+  // back, compensating records are appended locally and the record never
+  // goes to CAP. If `to` already moved the funds the rollback is impossible
+  // and the record joins the backlog like any other.
+  let sent = _mutate(|| -> Result<_, TxError> {
+    let undo = _send_undo(from, to, value.clone());
+    let fee = _apply_send(from, to, value.clone())?;
+    let insert = add_record_strict(
+      from,
+      _send_operation(to, Operation::Transfer),
+      from,
+      to,
+      value,
+      fee.clone(),
+      undo.time,
+      TransactionStatus::Succeeded,
+    );
+    Ok((SendUndo { fee, ..undo }, insert))
+  });
+  let (undo, insert) = _refund_on_error(token_fee.clone(), sent).await?;
+  let (index, ie) = match insert.await {
+    Ok(index) => return Ok(Nat::from(index)),
+    Err(failed) => failed,
+  };
+  let rolled_back = _mutate(|| {
+    let rolled_back = _rollback_send(undo);
+    if rolled_back.is_err() {
+      TXLOG.with(|t| t.borrow_mut().ie_records.push_back(ie));
+    }
+    rolled_back
+  });
+  if rolled_back.is_err() {
+    return Ok(Nat::from(index));
+  }
+  let unavailable = Err(TxError::Other("history unavailable".to_string()));
+  _refund_on_error(token_fee, unavailable).await
 }
 
-#[update(name = "transferFrom")]
-#[candid_method(update, rename = "transferFrom")]
-async fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt {
-  let owner = ic::caller();
-  let from_allowance = allowance(from, owner);
-  let fee = _get_fee();
-  if from_allowance < value.clone() + fee.clone() {
-    return Err(TxError::InsufficientAllowance);
+// Same as `transfer`, with an opaque memo of at most `MAX_MEMO_SIZE` bytes
+// kept next to the local record, see `getMemo`.
+#[update(name = "transferWithMemo")]
+#[candid_method(update, rename = "transferWithMemo")]
+async fn transfer_with_memo(to: Principal, value: Nat, memo: Vec<u8>) -> TxReceipt {
+  if memo.len() > MAX_MEMO_SIZE {
+    return Err(TxError::Other(format!(
+      "memo is longer than {} bytes",
+      MAX_MEMO_SIZE
+    )));
   }
-  let from_balance = balance_of(from);
-  if from_balance < value.clone() + fee.clone() {
-    return Err(TxError::InsufficientBalance);
+  let from = ic::caller();
+  let token_fee = _collect_token_fee(from, to, value.clone()).await?;
+  let sent = _mutate(|| -> Result<_, TxError> {
+    let insert = _send(from, to, value)?;
+    _memo_set(_last_tx_index(), memo);
+    Ok(insert)
+  });
+  _refund_on_error(token_fee, sent).await?.await
+}
+
+// Keeps every cycle attached to the call, so wallets can top the token up
+// directly. Returns the amount accepted.
+#[update(name = "acceptCycles")]
+#[candid_method(update, rename = "acceptCycles")]
+fn accept_cycles() -> u64 {
+  let available = ic::msg_cycles_available();
+  let accepted = ic::msg_cycles_accept(available);
+  ic::print(format!(
+    "accepted {} cycles from {}",
+    accepted,
+    ic::caller()
+  ));
+  accepted
+}
+
+// Sends the caller's whole balance minus the fee, read in the same message
+// that moves it so the caller ends up with nothing left.
+#[update(name = "transferAll")]
+#[candid_method(update, rename = "transferAll")]
+async fn transfer_all(to: Principal) -> TxReceipt {
+  let from = ic::caller();
+  let value = _transfer_all_value(from)?;
+  let token_fee = _collect_token_fee(from, to, value).await?;
+  let sent = _mutate(|| -> Result<_, TxError> {
+    let value = _transfer_all_value(from)?;
+    _send(from, to, value)
+  });
+  _refund_on_error(token_fee, sent).await?.await
+}
+
+// Same as `transfer`, also returning the fee and the sender's balance right
+// after the transfer.
+#[update(name = "transferDetailed")]
+#[candid_method(update, rename = "transferDetailed")]
+async fn transfer_detailed(to: Principal, value: Nat) -> Result<TransferResult, TxError> {
+  let from = ic::caller();
+  let token_fee = _collect_token_fee(from, to, value.clone()).await?;
+  let sent = _mutate(|| -> Result<_, TxError> {
+    let timestamp = ic::time();
+    let fee = _apply_send(from, to, value.clone())?;
+    let insert = add_record(
+      from,
+      _send_operation(to, Operation::Transfer),
+      from,
+      to,
+      value,
+      fee.clone(),
+      timestamp,
+      TransactionStatus::Succeeded,
+    );
+    Ok((insert, fee, balance_of(from), timestamp))
+  });
+  let (insert, fee_charged, new_sender_balance, timestamp) =
+    _refund_on_error(token_fee, sent).await?;
+  Ok(TransferResult {
+    tx_id: insert.await?,
+    fee_charged,
+    new_sender_balance,
+    timestamp,
+  })
+}
+
+// Same as `transfer`, but a `nonce` the caller already used returns the
+// receipt of that transfer instead of sending again. Only the last
+// `MAX_NONCES_PER_PRINCIPAL` nonces of each caller are remembered.
+#[update(name = "transferWithNonce")]
+#[candid_method(update, rename = "transferWithNonce")]
+async fn transfer_with_nonce(to: Principal, value: Nat, nonce: u64) -> TxReceipt {
+  let from = ic::caller();
+  if let Some(index) = _nonce_get(from, nonce) {
+    return Ok(index);
   }
-  _charge_fee(from, fee.clone());
-  _transfer(from, to, value.clone());
-  ALLOWS.with(|a| {
-    let mut allowances = a.borrow_mut();
-    match allowances.get(&from) {
-      Some(inner) => {
-        let result = inner.get(&owner).unwrap().clone();
-        let mut temp = inner.clone();
-        if result.clone() - value.clone() - fee.clone() != 0 {
-          temp.insert(owner, result.clone() - value.clone() - fee.clone());
-          allowances.insert(from, temp);
-        } else {
-          temp.remove(&owner);
-          if temp.len() == 0 {
-            allowances.remove(&from);
-          } else {
-            allowances.insert(from, temp);
-          }
-        }
-      }
-      None => {
-        assert!(false);
+  let token_fee = _collect_token_fee(from, to, value.clone()).await?;
+  // another call may have used the nonce while the fee token was charged
+  let sent = _mutate(|| -> Result<_, TxError> {
+    if let Some(index) = _nonce_get(from, nonce) {
+      return Ok(Err(index));
+    }
+    let insert = _send(from, to, value)?;
+    _nonce_set(from, nonce, Nat::from(_last_tx_index()));
+    Ok(Ok(insert))
+  });
+  match _refund_on_error(token_fee.clone(), sent).await? {
+    Ok(insert) => insert.await,
+    Err(index) => {
+      if let Some(fee) = token_fee {
+        _refund_token_fee(fee).await;
       }
+      Ok(index)
     }
+  }
+}
+
+#[update(name = "transferFrom")]
+#[candid_method(update, rename = "transferFrom")]
+async fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt {
+  let spender = ic::caller();
+  _check_allowance(from, spender, to, &value)?;
+  let token_fee = _collect_token_fee(from, to, value.clone()).await?;
+  let sent = _mutate(|| _send_from(spender, from, to, value));
+  _refund_on_error(token_fee, sent).await?.await
+}
+
+// Meta-transaction: a relayer submits a transfer `from` signed off-chain, so
+// `from` needs no allowance and no call of its own. `public_key` is the raw
+// Ed25519 key `from` is the self-authenticating principal of, the signature
+// is over `signed::message` and `nonce` has to be the next one of `from`,
+// see `getSignedNonce`. The fee is charged to `from` like for `transfer`.
+#[update(name = "transferFromSigned")]
+#[candid_method(update, rename = "transferFromSigned")]
+async fn transfer_from_signed(
+  from: Principal,
+  to: Principal,
+  value: Nat,
+  nonce: u64,
+  public_key: Vec<u8>,
+  signature: Vec<u8>,
+) -> TxReceipt {
+  let message = signed::message(ic::id(), to, &value, nonce);
+  signed::verify(from, &public_key, &message, &signature).map_err(TxError::Other)?;
+  _check_signed_nonce(from, nonce)?;
+  let token_fee = _collect_token_fee(from, to, value.clone()).await?;
+  let sent = _mutate(|| -> Result<_, TxError> {
+    _check_signed_nonce(from, nonce)?;
+    let fee = _apply_send(from, to, value.clone())?;
+    SIGNED_NONCES.with(|n| {
+      let mut nonces = n.borrow_mut();
+      nonces.insert(from, nonce + 1);
+    });
+    Ok(add_record(
+      ic::caller(),
+      _send_operation(to, Operation::TransferFrom),
+      from,
+      to,
+      value,
+      fee,
+      ic::time(),
+      TransactionStatus::Succeeded,
+    ))
   });
-  _history_inc();
-  add_record(
-    owner,
-    Operation::TransferFrom,
-    from,
-    to,
-    value,
-    fee,
-    ic::time(),
-    TransactionStatus::Succeeded,
-  )
-  .await
+  _refund_on_error(token_fee, sent).await?.await
+}
+
+// Same as `transferFrom`, also returning the caller's remaining allowance
+// over `from` next to the transaction index.
+#[update(name = "transferFromReturningAllowance")]
+#[candid_method(update, rename = "transferFromReturningAllowance")]
+async fn transfer_from_returning_allowance(
+  from: Principal,
+  to: Principal,
+  value: Nat,
+) -> Result<(Nat, Nat), TxError> {
+  let spender = ic::caller();
+  _check_allowance(from, spender, to, &value)?;
+  let token_fee = _collect_token_fee(from, to, value.clone()).await?;
+  let sent = _mutate(|| -> Result<_, TxError> {
+    let insert = _send_from(spender, from, to, value)?;
+    Ok((insert, allowance(from, spender)))
+  });
+  let (insert, remaining) = _refund_on_error(token_fee, sent).await?;
+  Ok((insert.await?, remaining))
 }
 
 #[update]
 #[candid_method(update)]
 async fn approve(spender: Principal, value: Nat) -> TxReceipt {
+  _mutate(|| _approve(ic::caller(), spender, value, None))?.await
+}
+
+// Same as `approve`, but only if the current allowance is still
+// `expected_current`, so a spend that landed in between isn't approved again.
+#[update(name = "approveChecked")]
+#[candid_method(update, rename = "approveChecked")]
+async fn approve_checked(spender: Principal, expected_current: Nat, new_value: Nat) -> TxReceipt {
   let owner = ic::caller();
-  let fee = _get_fee();
-  if balance_of(owner) < fee.clone() {
-    return Err(TxError::InsufficientBalance);
-  }
-  _charge_fee(owner, fee.clone());
-  let v = value.clone() + fee.clone();
-  ALLOWS.with(|a| {
-    let mut allowances = a.borrow_mut();
-    match allowances.get(&owner) {
-      Some(inner) => {
-        let mut temp = inner.clone();
-        if v.clone() != 0 {
-          temp.insert(spender, v.clone());
-          allowances.insert(owner, temp);
-        } else {
-          temp.remove(&spender);
-          if temp.len() == 0 {
-            allowances.remove(&owner);
-          } else {
-            allowances.insert(owner, temp);
-          }
-        }
-      }
-      None => {
-        if v.clone() != 0 {
-          let mut inner = HashMap::new();
-          inner.insert(spender, v.clone());
-          allowances.insert(owner, inner);
-        }
-      }
+  _mutate(|| {
+    if allowance(owner, spender) != expected_current {
+      return Err(TxError::AllowanceChanged);
     }
-  });
-
-  _history_inc();
-  add_record(
-    owner,
-    Operation::Approve,
-    owner,
-    spender,
-    v,
-    fee,
-    ic::time(),
-    TransactionStatus::Succeeded,
-  )
+    _approve(owner, spender, new_value, None)
+  })?
   .await
 }
 
+// Same as `approve`, but the allowance counts as zero from `expires_at`
+// (nanoseconds since the epoch) on.
+#[update(name = "approveWithExpiry")]
+#[candid_method(update, rename = "approveWithExpiry")]
+async fn approve_with_expiry(spender: Principal, value: Nat, expires_at: u64) -> TxReceipt {
+  if expires_at <= ic::time() {
+    return Err(TxError::Other("expiry is in the past".to_string()));
+  }
+  _mutate(|| _approve(ic::caller(), spender, value, Some(expires_at)))?.await
+}
+
+// ICRC-2 approve on top of the DIP20 allowances. `expected_allowance` is
+// compared with the current allowance in the same message that replaces it.
 #[update]
 #[candid_method(update)]
-async fn burn(amount: Nat) -> TxReceipt {
-  let caller = ic::caller();
-  let caller_balance = balance_of(caller);
-  if caller_balance.clone() < amount.clone() {
-    return Err(TxError::InsufficientBalance);
+async fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+  let canister = ic::id();
+  let accounts = (
+    icrc2::account_principal(canister, ic::caller(), &args.from_subaccount),
+    icrc2::account_principal(canister, args.spender.owner, &args.spender.subaccount),
+  );
+  let (owner, spender) = match accounts {
+    (Some(owner), Some(spender)) => (owner, spender),
+    _ => {
+      return Err(ApproveError::GenericError {
+        error_code: Nat::from(0),
+        message: "subaccounts must be 32 bytes".to_string(),
+      })
+    }
+  };
+  let fee = _get_fee(owner);
+  if args.fee.as_ref().is_some_and(|expected| *expected != fee) {
+    return Err(ApproveError::BadFee { expected_fee: fee });
   }
-  BALANCES.with(|b| {
-    let mut balances = b.borrow_mut();
-    balances.insert(caller, caller_balance - amount.clone());
-  });
-  STATS.with(|s| {
-    let mut stats = s.borrow_mut();
-    stats.total_supply -= amount.clone();
+  let now = ic::time();
+  if args.expires_at.is_some_and(|expires_at| expires_at <= now) {
+    return Err(ApproveError::Expired { ledger_time: now });
+  }
+  if args
+    .memo
+    .as_ref()
+    .is_some_and(|memo| memo.len() > MAX_MEMO_SIZE)
+  {
+    return Err(ApproveError::GenericError {
+      error_code: Nat::from(0),
+      message: format!("memo is longer than {} bytes", MAX_MEMO_SIZE),
+    });
+  }
+  _mutate(|| -> Result<_, ApproveError> {
+    let current_allowance = allowance(owner, spender);
+    if args
+      .expected_allowance
+      .is_some_and(|expected| expected != current_allowance)
+    {
+      return Err(ApproveError::AllowanceChanged { current_allowance });
+    }
+    let insert = _approve(owner, spender, args.amount, args.expires_at)
+      .map_err(|error| _to_approve_error(owner, error))?;
+    if let Some(memo) = args.memo {
+      _memo_set(_last_tx_index(), memo);
+    }
+    Ok(insert)
+  })?
+  .await
+  .map_err(|error| _to_approve_error(owner, error))
+}
+
+// Approves `spender`, which has to be a canister, and then calls
+// `spender.method(caller, value)` so it can act on the approval right away.
+// If that call fails the fee is refunded, and the previous allowance is
+// restored and recorded unless the spender already used the new one.
+#[update(name = "approveAndNotify")]
+#[candid_method(update, rename = "approveAndNotify")]
+async fn approve_and_notify(spender: Principal, value: Nat, method: String) -> TxReceipt {
+  if method.is_empty() || method.len() > MAX_METHOD_NAME_SIZE {
+    return Err(TxError::Other(format!(
+      "method name must be 1 to {} bytes",
+      MAX_METHOD_NAME_SIZE
+    )));
+  }
+  if !_is_canister(spender) {
+    return Err(TxError::Other("spender is not a canister".to_string()));
+  }
+  let owner = ic::caller();
+  let (insert, previous, previous_expiry, fee, (fee_to, burn_fees)) =
+    _mutate(|| -> Result<_, TxError> {
+      let previous = allowance(owner, spender);
+      let previous_expiry = _expiry_get(owner, spender);
+      let fee = _get_fee(owner);
+      let fee_destination = STATS.with(|s| {
+        let stats = s.borrow();
+        (stats.fee_to, stats.burn_fees)
+      });
+      let insert = _approve(owner, spender, value.clone(), None)?;
+      Ok((insert, previous, previous_expiry, fee, fee_destination))
+    })?;
+  let index = insert.await?;
+  let notified: Result<(), _> = ic::call(spender, method, (owner, value.clone())).await;
+  if let Err((_, reason)) = notified {
+    let (restored, refunded) = _mutate(|| {
+      let restored = if allowance(owner, spender) == value {
+        _allowance_set(owner, spender, previous.clone());
+        _expiry_set(owner, spender, previous_expiry);
+        _drawdown_approve(owner, spender, previous.clone());
+        _history_inc();
+        Some(add_record(
+          owner,
+          Operation::Approve,
+          owner,
+          spender,
+          previous,
+          Nat::from(0),
+          ic::time(),
+          TransactionStatus::Succeeded,
+        ))
+      } else {
+        None
+      };
+      (restored, _refund_fee(owner, fee, fee_to, burn_fees))
+    });
+    if let Some(restored) = restored {
+      restored.await?;
+    }
+    if let Some(refunded) = refunded {
+      refunded.await?;
+    }
+    return Err(TxError::Other(format!(
+      "notifying the spender failed: {}",
+      reason
+    )));
+  }
+  Ok(index)
+}
+
+// Applies several approvals in one call. The fee is charged once per
+// approval and the caller has to cover all of them up front, otherwise the
+// result is that single error.
+#[update(name = "batchApprove")]
+#[candid_method(update, rename = "batchApprove")]
+async fn batch_approve(approvals: Vec<(Principal, Nat)>) -> Vec<TxReceipt> {
+  let count = approvals.len();
+  let pending = _mutate(|| -> Result<_, TxError> {
+    let owner = ic::caller();
+    _check_blocked(&[owner])?;
+    let fee = _get_fee(owner);
+    if balance_of(owner) < fee.clone() * Nat::from(count) {
+      return Err(TxError::InsufficientBalance);
+    }
+    Ok(
+      approvals
+        .into_iter()
+        .map(|(spender, value)| _approve(owner, spender, value, None))
+        .collect::<Vec<_>>(),
+    )
   });
-  _history_inc();
-  add_record(
-    caller,
-    Operation::Burn,
-    caller,
-    caller,
-    amount,
-    Nat::from(0),
-    ic::time(),
-    TransactionStatus::Succeeded,
-  )
+  let pending = match pending {
+    Ok(pending) => pending,
+    Err(error) => return vec![Err(error)],
+  };
+  let mut receipts = Vec::new();
+  for record in pending {
+    receipts.push(match record {
+      Ok(insert) => insert.await,
+      Err(error) => Err(error),
+    });
+  }
+  receipts
+}
+
+#[update]
+#[candid_method(update)]
+async fn burn(amount: Nat) -> TxReceipt {
+  _mutate(|| -> Result<_, TxError> {
+    let caller = ic::caller();
+    _burn(caller, amount.clone())?;
+    _history_inc();
+    Ok(add_record(
+      caller,
+      Operation::Burn,
+      caller,
+      caller,
+      amount,
+      Nat::from(0),
+      ic::time(),
+      TransactionStatus::Succeeded,
+    ))
+  })?
   .await
 }
 
@@ -318,9 +1111,142 @@ fn balance_of(id: Principal) -> Nat {
   })
 }
 
+// Balances of `ids` in the same order, at most `MAX_BATCH_SIZE` at a time.
+#[query(name = "balanceOfBatch")]
+#[candid_method(query, rename = "balanceOfBatch")]
+fn balance_of_batch(ids: Vec<Principal>) -> Vec<Nat> {
+  assert!(
+    ids.len() <= MAX_BATCH_SIZE,
+    "at most {} principals per batch",
+    MAX_BATCH_SIZE
+  );
+  BALANCES.with(|b| {
+    let balances = b.borrow();
+    ids
+      .iter()
+      .map(|id| balances.get(id).cloned().unwrap_or_else(|| Nat::from(0)))
+      .collect()
+  })
+}
+
+// Balance of `id` when snapshot `snapshot_id` was taken, `None` for
+// snapshots that were never taken or have been evicted.
+#[query(name = "balanceOfAt")]
+#[candid_method(query, rename = "balanceOfAt")]
+fn balance_of_at(id: Principal, snapshot_id: u64) -> Option<Nat> {
+  SNAPSHOTS.with(|s| {
+    let snapshots = s.borrow();
+    snapshots.balance_at(snapshot_id, id, balance_of(id))
+  })
+}
+
+// Total supply minus the balances of the principals excluded from
+// circulation, see `setExcludedFromCirculating`.
+#[query(name = "circulatingSupply")]
+#[candid_method(query, rename = "circulatingSupply")]
+fn circulating_supply() -> Nat {
+  let excluded = EXCLUDED.with(|e| {
+    let excluded = e.borrow();
+    excluded
+      .iter()
+      .fold(Nat::from(0), |sum, who| sum + balance_of(*who))
+  });
+  _try_sub(total_supply(), excluded).unwrap_or_else(|| Nat::from(0))
+}
+
+#[query(name = "getExcludedFromCirculating")]
+#[candid_method(query, rename = "getExcludedFromCirculating")]
+fn get_excluded_from_circulating() -> Vec<Principal> {
+  EXCLUDED.with(|e| {
+    let excluded = e.borrow();
+    let mut excluded = Vec::from_iter(excluded.iter().cloned());
+    excluded.sort();
+    excluded
+  })
+}
+
+#[query(name = "getFeeExempt")]
+#[candid_method(query, rename = "getFeeExempt")]
+fn get_fee_exempt() -> Vec<Principal> {
+  FEE_EXEMPT.with(|f| {
+    let fee_exempt = f.borrow();
+    let mut fee_exempt = Vec::from_iter(fee_exempt.iter().cloned());
+    fee_exempt.sort();
+    fee_exempt
+  })
+}
+
+#[query(name = "getBurnSinks")]
+#[candid_method(query, rename = "getBurnSinks")]
+fn get_burn_sinks() -> Vec<Principal> {
+  BURN_SINKS.with(|b| {
+    let burn_sinks = b.borrow();
+    let mut burn_sinks = Vec::from_iter(burn_sinks.iter().cloned());
+    burn_sinks.sort();
+    burn_sinks
+  })
+}
+
+// Balance of `id` as a decimal string using the token's `decimals`, e.g.
+// "12.3456" for 123456 with 4 decimals. Trailing zeros are trimmed.
+#[query(name = "formattedBalanceOf")]
+#[candid_method(query, rename = "formattedBalanceOf")]
+fn formatted_balance_of(id: Principal) -> String {
+  let digits = balance_of(id).0.to_string();
+  let decimals = STATS.with(|s| s.borrow().decimals) as usize;
+  if decimals == 0 {
+    return digits;
+  }
+  let digits = format!("{:0>width$}", digits, width = decimals + 1);
+  let (whole, fraction) = digits.split_at(digits.len() - decimals);
+  let fraction = fraction.trim_end_matches('0');
+  if fraction.is_empty() {
+    whole.to_string()
+  } else {
+    format!("{}.{}", whole, fraction)
+  }
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+  let canister = ic::id();
+  let accounts = (
+    icrc2::account_principal(canister, args.account.owner, &args.account.subaccount),
+    icrc2::account_principal(canister, args.spender.owner, &args.spender.subaccount),
+  );
+  let (owner, spender) = match accounts {
+    (Some(owner), Some(spender)) => (owner, spender),
+    _ => {
+      return Allowance {
+        allowance: Nat::from(0),
+        expires_at: None,
+      }
+    }
+  };
+  Allowance {
+    allowance: allowance(owner, spender),
+    expires_at: _expiry_get(owner, spender),
+  }
+}
+
+// Principal `owner`'s 32 byte `subaccount` maps to on this token, see
+// `icrc2::derive_principal`. ICRC-2 approvals and allowances of the
+// subaccount are those of this principal.
+#[query(name = "deriveSubaccountPrincipal")]
+#[candid_method(query, rename = "deriveSubaccountPrincipal")]
+fn derive_subaccount_principal(owner: Principal, subaccount: Vec<u8>) -> Principal {
+  let subaccount =
+    <[u8; 32]>::try_from(subaccount.as_slice()).expect("subaccount must be 32 bytes");
+  icrc2::derive_principal(ic::id(), owner, &subaccount)
+}
+
 #[query]
 #[candid_method(query)]
 fn allowance(owner: Principal, spender: Principal) -> Nat {
+  if _is_expired(owner, spender) {
+    return Nat::from(0);
+  }
   ALLOWS.with(|a| {
     let allowances = a.borrow();
     match allowances.get(&owner) {
@@ -333,6 +1259,28 @@ fn allowance(owner: Principal, spender: Principal) -> Nat {
   })
 }
 
+// The last `limit` points of the supply history, oldest first. A point is
+// recorded on every mint, burn and burned fee, transfers don't change the
+// supply.
+#[query(name = "getSupplyHistory")]
+#[candid_method(query, rename = "getSupplyHistory")]
+fn get_supply_history(limit: usize) -> Vec<(u64, Nat)> {
+  SUPPLY_HISTORY.with(|h| {
+    let supply_history = h.borrow();
+    let skip = supply_history.len().saturating_sub(limit);
+    supply_history.iter().skip(skip).cloned().collect()
+  })
+}
+
+#[query(name = "totalSupplyAt")]
+#[candid_method(query, rename = "totalSupplyAt")]
+fn total_supply_at(snapshot_id: u64) -> Option<Nat> {
+  SNAPSHOTS.with(|s| {
+    let snapshots = s.borrow();
+    snapshots.supplies.get(&snapshot_id).cloned()
+  })
+}
+
 #[query]
 #[candid_method(query)]
 fn logo() -> String {
@@ -342,6 +1290,15 @@ fn logo() -> String {
   })
 }
 
+// Hex encoded SHA-256 of the logo, lets frontends tell whether the logo
+// changed without fetching it.
+#[query(name = "getLogoHash")]
+#[candid_method(query, rename = "getLogoHash")]
+fn get_logo_hash() -> String {
+  let digest = STATS.with(|s| Sha256::digest(s.borrow().logo.as_bytes()));
+  digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 #[query]
 #[candid_method(query)]
 fn name() -> String {
@@ -369,6 +1326,26 @@ fn decimals() -> u8 {
   })
 }
 
+// `getName`, `getSymbol` and `getDecimals` are aliases for tooling that
+// expects the DFinance style method names.
+#[query(name = "getName")]
+#[candid_method(query, rename = "getName")]
+fn get_name() -> String {
+  name()
+}
+
+#[query(name = "getSymbol")]
+#[candid_method(query, rename = "getSymbol")]
+fn get_symbol() -> String {
+  symbol()
+}
+
+#[query(name = "getDecimals")]
+#[candid_method(query, rename = "getDecimals")]
+fn get_decimals() -> u8 {
+  decimals()
+}
+
 #[query(name = "totalSupply")]
 #[candid_method(query, rename = "totalSupply")]
 fn total_supply() -> Nat {
@@ -387,6 +1364,69 @@ fn owner() -> Principal {
   })
 }
 
+// The caller as this canister sees it, for debugging `Unauthorized` errors.
+#[query(name = "whoAmI")]
+#[candid_method(query, rename = "whoAmI")]
+fn who_am_i() -> Principal {
+  ic::caller()
+}
+
+// Roles `who` holds: "owner" for the principal the owner-only methods accept
+// and "pending_owner" for the one that can call `acceptOwnership`.
+#[query(name = "getRoles")]
+#[candid_method(query, rename = "getRoles")]
+fn get_roles(who: Principal) -> Vec<String> {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    let mut roles = Vec::new();
+    if who == stats.owner {
+      roles.push("owner".to_string());
+    }
+    if stats.pending_owner == Some(who) {
+      roles.push("pending_owner".to_string());
+    }
+    roles
+  })
+}
+
+#[query(name = "getOperationCounts")]
+#[candid_method(query, rename = "getOperationCounts")]
+fn get_operation_counts() -> OperationCounts {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    OperationCounts {
+      mint: stats.mint_count,
+      burn: stats.burn_count,
+      transfer: stats.transfer_count,
+      approve: stats.approve_count,
+    }
+  })
+}
+
+// Nonce the next `transferFromSigned` from `owner` has to carry.
+#[query(name = "getSignedNonce")]
+#[candid_method(query, rename = "getSignedNonce")]
+fn get_signed_nonce(owner: Principal) -> u64 {
+  SIGNED_NONCES.with(|n| n.borrow().get(&owner).copied().unwrap_or(0))
+}
+
+#[query(name = "getPendingOwner")]
+#[candid_method(query, rename = "getPendingOwner")]
+fn get_pending_owner() -> Option<Principal> {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    stats.pending_owner
+  })
+}
+
+// Every field of the token's stats in one call, for monitoring. All of it,
+// `fee_to` included, is public anyway through other queries.
+#[query(name = "getStats")]
+#[candid_method(query, rename = "getStats")]
+fn get_stats() -> StatsData {
+  STATS.with(|s| s.borrow().clone())
+}
+
 #[query(name = "getMetadata")]
 #[candid_method(query, rename = "getMetadata")]
 fn get_metadata() -> Metadata {
@@ -404,6 +1444,26 @@ fn get_metadata() -> Metadata {
   })
 }
 
+#[query(name = "getExtendedMetadata")]
+#[candid_method(query, rename = "getExtendedMetadata")]
+fn get_extended_metadata() -> ExtendedMetadata {
+  let metadata = get_metadata();
+  STATS.with(|s| {
+    let stats = s.borrow();
+    ExtendedMetadata {
+      metadata,
+      feeDestination: get_fee_destination(),
+      feeToken: stats.fee_token,
+      feeTokenAmount: stats.fee_token_amount.clone(),
+      minApproval: stats.min_approval.clone(),
+      maxHolders: stats.max_holders,
+      maxRepresentableSupply: _get_max_representable_supply(),
+    }
+  })
+}
+
+// Number of operations recorded by this canister, including the genesis
+// mint. It isn't read back from CAP, see `reconcileHistorySize`.
 #[query(name = "historySize")]
 #[candid_method(query, rename = "historySize")]
 fn history_size() -> usize {
@@ -413,25 +1473,124 @@ fn history_size() -> usize {
   })
 }
 
+#[query(name = "getWiring")]
+#[candid_method(query, rename = "getWiring")]
+fn get_wiring() -> Wiring {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    Wiring {
+      canister: ic::id(),
+      cap: stats.cap,
+      fee_token: stats.fee_token,
+    }
+  })
+}
+
+// CAP principal the token was last pointed at, `None` for tokens deployed
+// before it was recorded and never re-pointed with `setCapRoot`.
+#[query(name = "getCapRoot")]
+#[candid_method(query, rename = "getCapRoot")]
+fn get_cap_root() -> Option<Principal> {
+  STATS.with(|s| s.borrow().cap)
+}
+
+#[query(name = "getUpgradeInfo")]
+#[candid_method(query, rename = "getUpgradeInfo")]
+fn get_upgrade_info() -> UpgradeInfo {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    UpgradeInfo {
+      upgrade_count: stats.upgrade_count,
+      last_upgrade_time: stats.last_upgrade_time,
+      data_version: DATA_VERSION,
+    }
+  })
+}
+
+#[query(name = "getDeployTime")]
+#[candid_method(query, rename = "getDeployTime")]
+fn get_deploy_time() -> u64 {
+  STATS.with(|s| s.borrow().deploy_time)
+}
+
+// Nanoseconds since the token was deployed.
+#[query(name = "getAge")]
+#[candid_method(query, rename = "getAge")]
+fn get_age() -> u64 {
+  ic::time().saturating_sub(get_deploy_time())
+}
+
+#[query(name = "getCyclesBalance")]
+#[candid_method(query, rename = "getCyclesBalance")]
+fn get_cycles_balance() -> u64 {
+  ic::balance()
+}
+
 #[query(name = "getTokenInfo")]
 #[candid_method(query, rename = "getTokenInfo")]
 fn get_token_info() -> TokenInfo {
   STATS.with(|s| {
     let stats = s.borrow();
-    BALANCES.with(|b| {
-      let balances = b.borrow();
-      TokenInfo {
-        metadata: get_metadata(),
-        feeTo: stats.fee_to,
-        historySize: stats.history_size,
-        deployTime: stats.deploy_time,
-        holderNumber: balances.len(),
-        cycles: ic::balance(),
-      }
-    })
+    TokenInfo {
+      metadata: get_metadata(),
+      feeTo: stats.fee_to,
+      historySize: stats.history_size,
+      deployTime: stats.deploy_time,
+      holderNumber: get_holders_count(),
+      blockedNumber: BLOCKLIST.with(|b| b.borrow().len()),
+      cycles: ic::balance(),
+    }
+  })
+}
+
+#[query(name = "getHoldersCount")]
+#[candid_method(query, rename = "getHoldersCount")]
+fn get_holders_count() -> usize {
+  BALANCES.with(|b| {
+    let balances = b.borrow();
+    balances.values().filter(|v| **v != 0).count()
+  })
+}
+
+// Number of holders whose balance is strictly greater than `min`.
+#[query(name = "getTotalHoldersAbove")]
+#[candid_method(query, rename = "getTotalHoldersAbove")]
+fn get_total_holders_above(min: Nat) -> usize {
+  BALANCES.with(|b| {
+    let balances = b.borrow();
+    balances.values().filter(|balance| **balance > min).count()
   })
 }
 
+// Number of holders per balance range for ascending `buckets`: entry `i`
+// counts balances in `[buckets[i], buckets[i + 1])`, the last one is open
+// ended. Balances below `buckets[0]` aren't counted.
+#[query(name = "getBalanceDistribution")]
+#[candid_method(query, rename = "getBalanceDistribution")]
+fn get_balance_distribution(buckets: Vec<Nat>) -> Vec<usize> {
+  let mut counts = vec![0; buckets.len()];
+  BALANCES.with(|b| {
+    let balances = b.borrow();
+    for balance in balances.values().filter(|balance| **balance != 0) {
+      let above = buckets.iter().take_while(|bound| *bound <= balance).count();
+      if above > 0 {
+        counts[above - 1] += 1;
+      }
+    }
+  });
+  counts
+}
+
+// Total supply divided by the number of holders, rounded down.
+#[query(name = "getMeanBalance")]
+#[candid_method(query, rename = "getMeanBalance")]
+fn get_mean_balance() -> Nat {
+  match get_holders_count() {
+    0 => Nat::from(0),
+    holders => total_supply() / Nat::from(holders),
+  }
+}
+
 #[query(name = "getHolders")]
 #[candid_method(query, rename = "getHolders")]
 fn get_holders(start: usize, limit: usize) -> Vec<(Principal, Nat)> {
@@ -439,15 +1598,82 @@ fn get_holders(start: usize, limit: usize) -> Vec<(Principal, Nat)> {
     let balances = b.borrow();
     let mut balance = Vec::new();
     for (k, v) in balances.iter() {
-      balance.push((k.clone(), v.clone()));
+      if *v != 0 {
+        balance.push((*k, v.clone()));
+      }
+    }
+    // Ties are broken on the principal bytes so pages line up across calls.
+    balance.sort_by(|a, b| {
+      b.1
+        .cmp(&a.1)
+        .then_with(|| a.0.as_slice().cmp(b.0.as_slice()))
+    });
+    let start = start.min(balance.len());
+    let end = start.saturating_add(limit).min(balance.len());
+    balance[start..end].to_vec()
+  })
+}
+
+// Balances in principal byte order after `cursor`, at most `limit` of them,
+// plus the cursor for the next page or `None` after the last one. `limit`
+// has to be positive. Pages come from separate calls, balances may change
+// between them.
+#[query(name = "exportBalances")]
+#[candid_method(query, rename = "exportBalances")]
+fn export_balances(
+  cursor: Option<Principal>,
+  limit: usize,
+) -> (Vec<(Principal, Nat)>, Option<Principal>) {
+  assert!(limit > 0, "limit must be positive");
+  BALANCES.with(|b| {
+    let balances = b.borrow();
+    let mut page: Vec<(&Principal, &Nat)> = balances
+      .iter()
+      .filter(|(who, _)| cursor.is_none_or(|cursor| who.as_slice() > cursor.as_slice()))
+      .collect();
+    let more = page.len() > limit;
+    if more {
+      // only the first `limit` entries need to be sorted
+      page.select_nth_unstable_by(limit, |a, b| a.0.as_slice().cmp(b.0.as_slice()));
+      page.truncate(limit);
     }
-    balance.sort_by(|a, b| b.1.cmp(&a.1));
-    let limit: usize = if start + limit > balance.len() {
-      balance.len() - start
+    page.sort_unstable_by(|a, b| a.0.as_slice().cmp(b.0.as_slice()));
+    let next = if more {
+      page.last().map(|(who, _)| **who)
     } else {
-      limit
+      None
     };
-    balance[start..start + limit].to_vec()
+    let page = page
+      .into_iter()
+      .map(|(who, balance)| (*who, balance.clone()))
+      .collect();
+    (page, next)
+  })
+}
+
+// Allowance `spender` was last approved over `owner`, zero for approvals
+// from before draw-downs were tracked.
+#[query(name = "getOriginalAllowance")]
+#[candid_method(query, rename = "getOriginalAllowance")]
+fn get_original_allowance(owner: Principal, spender: Principal) -> Nat {
+  DRAWDOWNS.with(|d| {
+    let drawdowns = d.borrow();
+    drawdowns
+      .get(&(owner, spender))
+      .map_or_else(|| Nat::from(0), |(approved, _)| approved.clone())
+  })
+}
+
+// Amount `spender` took out of the allowance since it was last approved,
+// fees included.
+#[query(name = "getSpentAllowance")]
+#[candid_method(query, rename = "getSpentAllowance")]
+fn get_spent_allowance(owner: Principal, spender: Principal) -> Nat {
+  DRAWDOWNS.with(|d| {
+    let drawdowns = d.borrow();
+    drawdowns
+      .get(&(owner, spender))
+      .map_or_else(|| Nat::from(0), |(_, spent)| spent.clone())
   })
 }
 
@@ -464,163 +1690,2369 @@ fn get_allowance_size() -> usize {
   })
 }
 
+// Spenders `who` approved and the allowance of each, ordered by spender.
 #[query(name = "getUserApprovals")]
 #[candid_method(query, rename = "getUserApprovals")]
 fn get_user_approvals(who: Principal) -> Vec<(Principal, Nat)> {
-  ALLOWS.with(|a| {
+  let mut approvals = ALLOWS.with(|a| {
     let allowances = a.borrow();
     match allowances.get(&who) {
-      Some(allow) => Vec::from_iter(allow.clone().into_iter()),
+      Some(allow) => Vec::from_iter(
+        allow
+          .clone()
+          .into_iter()
+          .filter(|(spender, _)| !_is_expired(who, *spender)),
+      ),
       None => Vec::new(),
     }
-  })
-}
-
-/* CONTROLLER FNS */
-
-#[update(guard = "_is_auth")]
-#[candid_method(update, rename = "mint")]
-async fn mint(to: Principal, amount: Nat) -> TxReceipt {
-  let caller = ic::caller();
-  let to_balance = balance_of(to);
-
-  BALANCES.with(|b| {
-    let mut balances = b.borrow_mut();
-    balances.insert(to, to_balance + amount.clone());
   });
-  STATS.with(|s| {
-    let mut stats = s.borrow_mut();
-    stats.total_supply += amount.clone();
-  });
-  _history_inc();
-  add_record(
-    caller,
-    Operation::Mint,
-    caller,
-    to,
-    amount,
-    Nat::from(0),
-    ic::time(),
-    TransactionStatus::Succeeded,
-  )
-  .await
+  approvals.sort_by_key(|a| a.0);
+  approvals
 }
 
-#[update(name = "setName", guard = "_is_auth")]
-#[candid_method(update, rename = "setName")]
-fn set_name(name: String) {
-  STATS.with(|s| {
-    let mut stats = s.borrow_mut();
-    stats.name = name;
+// Owners that approved `spender` and the allowance of each, ordered by owner.
+#[query(name = "getApprovalsForSpender")]
+#[candid_method(query, rename = "getApprovalsForSpender")]
+fn get_approvals_for_spender(spender: Principal) -> Vec<(Principal, Nat)> {
+  let mut owners = SPENDERS.with(|s| {
+    let spenders = s.borrow();
+    spenders
+      .get(&spender)
+      .map_or_else(Vec::new, |owners| Vec::from_iter(owners.iter().cloned()))
   });
+  owners.sort();
+  owners
+    .into_iter()
+    .map(|owner| (owner, allowance(owner, spender)))
+    .filter(|(_, value)| *value != 0)
+    .collect()
 }
 
-#[update(name = "setLogo", guard = "_is_auth")]
-#[candid_method(update, rename = "setLogo")]
-fn set_logo(logo: String) {
-  STATS.with(|s| {
-    let mut stats = s.borrow_mut();
-    stats.logo = logo;
-  });
+// Page of the approval graph ordered by owner and then spender. Every call
+// collects and sorts all allowances, so it is O(n log n) in their number.
+#[query(name = "allowanceEdges")]
+#[candid_method(query, rename = "allowanceEdges")]
+fn allowance_edges(start: usize, limit: usize) -> Vec<AllowanceEdge> {
+  ALLOWS.with(|a| {
+    let allowances = a.borrow();
+    let mut edges = Vec::new();
+    for (owner, inner) in allowances.iter() {
+      for (spender, value) in inner.iter() {
+        if !_is_expired(*owner, *spender) {
+          edges.push(AllowanceEdge {
+            owner: *owner,
+            spender: *spender,
+            value: value.clone(),
+          });
+        }
+      }
+    }
+    edges.sort_by(|a, b| {
+      a.owner
+        .as_slice()
+        .cmp(b.owner.as_slice())
+        .then_with(|| a.spender.as_slice().cmp(b.spender.as_slice()))
+    });
+    edges.into_iter().skip(start).take(limit).collect()
+  })
 }
 
-#[update(name = "setFee", guard = "_is_auth")]
-#[candid_method(update, rename = "setFee")]
-fn set_fee(fee: Nat) {
-  STATS.with(|s| {
-    let mut stats = s.borrow_mut();
-    stats.fee = fee;
-  });
+// Maximum amount `account` could `transfer` right now, i.e. after every
+// restriction and the fee have been applied, along with why it is zero.
+#[query(name = "spendableNow")]
+#[candid_method(query, rename = "spendableNow")]
+fn spendable_now(account: Principal) -> SpendableNow {
+  let nothing = |reason: &str| SpendableNow {
+    amount: Nat::from(0),
+    reason_if_zero: Some(reason.to_string()),
+  };
+  if is_blocked(account) {
+    return nothing("account blocked");
+  }
+  if _check_sale_window(account).is_err() {
+    return nothing("outside sale window");
+  }
+  if _check_rate_limit(account).is_err() {
+    return nothing("rate limited");
+  }
+  if _check_cooldown(account).is_err() {
+    return nothing("cooldown");
+  }
+  if _check_cycles().is_err() {
+    return nothing("low cycles");
+  }
+  let balance = balance_of(account);
+  if balance == 0 {
+    return nothing("zero balance");
+  }
+  match _try_sub(balance, _transfer_fee(account)) {
+    Some(amount) if amount > 0 => SpendableNow {
+      amount,
+      reason_if_zero: None,
+    },
+    _ => nothing("balance does not cover the fee"),
+  }
 }
 
-#[update(name = "setFeeTo", guard = "_is_auth")]
-#[candid_method(update, rename = "setFeeTo")]
-fn set_fee_to(fee_to: Principal) {
-  STATS.with(|s| {
-    let mut stats = s.borrow_mut();
-    stats.fee_to = fee_to;
-  });
+#[query(name = "getFeeTo")]
+#[candid_method(query, rename = "getFeeTo")]
+fn get_fee_to() -> Principal {
+  STATS.with(|s| s.borrow().fee_to)
 }
 
-#[update(name = "setOwner", guard = "_is_auth")]
-#[candid_method(update, rename = "setOwner")]
-fn set_owner(owner: Principal) {
+// Token canister transfer fees are collected in, and the amount collected per
+// transfer. `None` means fees are taken in this token.
+#[query(name = "getFeeToken")]
+#[candid_method(query, rename = "getFeeToken")]
+fn get_fee_token() -> (Option<Principal>, Nat) {
   STATS.with(|s| {
-    let mut stats = s.borrow_mut();
-    stats.owner = owner;
-  });
+    let stats = s.borrow();
+    (stats.fee_token, stats.fee_token_amount.clone())
+  })
 }
 
-/* INTERNAL FNS */
-
-// TODO: use controllers for ownership
-// this will require the canister to be a controller of itself (like dip721)
-fn _is_auth() -> Result<(), String> {
+// Maximum number of transfers and the window in nanoseconds they are counted
+// over, `None` when transfers aren't rate limited.
+#[query(name = "getRateLimit")]
+#[candid_method(query, rename = "getRateLimit")]
+fn get_rate_limit() -> Option<(usize, u64)> {
   STATS.with(|s| {
     let stats = s.borrow();
-    if ic::caller() == stats.owner {
-      Ok(())
-    } else {
-      Err("Error: Unauthorized principal ID".to_string())
-    }
+    stats.rate_limit
   })
 }
 
-fn _balance_ins(from: Principal, value: Nat) {
-  BALANCES.with(|b| {
-    let mut balances = b.borrow_mut();
-    balances.insert(from, value);
-  });
+// Transfers `account` can still make in the current window, `None` when it
+// is not limited.
+#[query(name = "getRateLimitRemaining")]
+#[candid_method(query, rename = "getRateLimitRemaining")]
+fn get_rate_limit_remaining(account: Principal) -> Option<usize> {
+  _rate_limit_remaining(account)
 }
 
-fn _balance_rem(from: Principal) {
-  BALANCES.with(|b| {
-    let mut balances = b.borrow_mut();
-    balances.remove(&from);
-  });
+// Smallest non-zero allowance `approve` accepts, 0 when any is.
+#[query(name = "getMinApproval")]
+#[candid_method(query, rename = "getMinApproval")]
+fn get_min_approval() -> Nat {
+  _get_min_approval()
 }
 
-fn _transfer(from: Principal, to: Principal, value: Nat) {
-  let from_balance = balance_of(from);
-  let from_balance_new = from_balance - value.clone();
-
-  // TODO: check this logic ↴
-  if from_balance_new != 0 {
-    _balance_ins(from, from_balance_new);
-  } else {
-    _balance_rem(from)
-  }
-  let to_balance = balance_of(to);
-  let to_balance_new = to_balance + value;
-  if to_balance_new != 0 {
-    _balance_ins(to, to_balance_new);
-  }
+// Whether transfers are rejected and rolled back when their CAP insert fails.
+#[query(name = "getRequireCapSuccess")]
+#[candid_method(query, rename = "getRequireCapSuccess")]
+fn get_require_cap_success() -> bool {
+  _require_cap_success()
 }
 
-fn _charge_fee(user: Principal, fee: Nat) {
+// Whether charged fees are burned instead of being sent to `fee_to`.
+// A burned fee lowers the total supply and gets a `Burn` record of its own
+// from the payer, right before the record that charged it.
+#[query(name = "getFeeDestination")]
+#[candid_method(query, rename = "getFeeDestination")]
+fn get_fee_destination() -> FeeDestination {
   STATS.with(|s| {
     let stats = s.borrow();
-    if stats.fee > Nat::from(0) {
-      _transfer(user, stats.fee_to, fee);
+    if stats.burn_fees {
+      FeeDestination::Burn
+    } else {
+      FeeDestination::Account(stats.fee_to)
     }
-  });
+  })
 }
 
-fn _get_fee() -> Nat {
+#[query(name = "getBurnFees")]
+#[candid_method(query, rename = "getBurnFees")]
+fn get_burn_fees() -> bool {
   STATS.with(|s| {
     let stats = s.borrow();
-    stats.fee.clone()
+    stats.burn_fees
   })
 }
 
-fn _get_owner() -> Principal {
+// Start and end of the sale window in nanoseconds since the epoch.
+#[query(name = "getSaleWindow")]
+#[candid_method(query, rename = "getSaleWindow")]
+fn get_sale_window() -> (Option<u64>, Option<u64>) {
   STATS.with(|s| {
     let stats = s.borrow();
-    stats.owner
+    (stats.sale_start, stats.sale_end)
   })
 }
 
-fn _history_inc() {
+#[query(name = "getTransactionsByUser")]
+#[candid_method(query, rename = "getTransactionsByUser")]
+fn get_transactions_by_user(who: Principal, start: usize, limit: usize) -> Vec<TxRecord> {
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    match history.user_index.get(&who) {
+      Some(indexes) => indexes
+        .iter()
+        .skip(start)
+        .take(limit)
+        .filter_map(|i| history.get(*i).cloned())
+        .collect(),
+      None => Vec::new(),
+    }
+  })
+}
+
+// Status of a cached record, `None` once it's evicted or for an unknown
+// index. Stored records never change, a strict transfer that gets rolled back
+// is followed by compensating records instead of being marked `Failed`.
+#[query(name = "getTransactionStatus")]
+#[candid_method(query, rename = "getTransactionStatus")]
+fn get_transaction_status(index: Nat) -> Option<TransactionStatus> {
+  let index = usize::try_from(index.0).ok()?;
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    history.get(index).map(|record| record.status.clone())
+  })
+}
+
+// Cached records of operation `op`, oldest first.
+#[query(name = "getTransactionsByOperation")]
+#[candid_method(query, rename = "getTransactionsByOperation")]
+fn get_transactions_by_operation(op: Operation, start: usize, limit: usize) -> Vec<TxRecord> {
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    let indexes = history
+      .op_index
+      .as_ref()
+      .and_then(|op_index| op_index.get(&TxHistory::op_key(&op)));
+    match indexes {
+      Some(indexes) => indexes
+        .iter()
+        .skip(start)
+        .take(limit)
+        .filter_map(|i| history.get(*i).cloned())
+        .collect(),
+      None => Vec::new(),
+    }
+  })
+}
+
+// Cached record whose content hash is `hash`, see `TxHistory::content_hash`
+// for how to compute it. With several matching records the oldest is found.
+#[query(name = "getTransactionByHash")]
+#[candid_method(query, rename = "getTransactionByHash")]
+fn get_transaction_by_hash(hash: Vec<u8>) -> Option<TxRecord> {
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    let index = *history.content_index.get(&hash)?;
+    history.get(index).cloned()
+  })
+}
+
+// Cached records with an index above `after_index`, oldest first, for
+// indexers catching up. Records evicted from the cache are skipped, see
+// `historyCacheInfo`. `more` is set when the page was cut at `limit`.
+#[query(name = "getTransactionsSince")]
+#[candid_method(query, rename = "getTransactionsSince")]
+fn get_transactions_since(after_index: Nat, limit: usize) -> TransactionsPage {
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    let start = usize::try_from(after_index.0)
+      .map_or(usize::MAX, |index| index.saturating_add(1))
+      .saturating_sub(history.evicted);
+    let available = history.records.len().saturating_sub(start);
+    TransactionsPage {
+      records: history
+        .records
+        .iter()
+        .skip(start)
+        .take(limit)
+        .cloned()
+        .collect(),
+      more: available > limit,
+    }
+  })
+}
+
+// Describes the local history cache. `complete` is false once records have
+// been evicted, older records then have to be read from CAP.
+#[query(name = "historyCacheInfo")]
+#[candid_method(query, rename = "historyCacheInfo")]
+fn history_cache_info() -> HistoryCacheInfo {
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    HistoryCacheInfo {
+      cached_count: history.records.len(),
+      oldest_index: Nat::from(history.evicted),
+      complete: history.evicted == 0,
+    }
+  })
+}
+
+// Number of records whose CAP insert failed, waiting to be retried. Records
+// queued for the next batch are counted by `getCapQueueSize`.
+#[query(name = "getCapBacklogSize")]
+#[candid_method(query, rename = "getCapBacklogSize")]
+fn get_cap_backlog_size() -> usize {
+  TXLOG.with(|t| {
+    let tx_log = t.borrow();
+    tx_log.ie_records.len()
+  })
+}
+
+// Number of records queued for the next CAP batch, see `setCapBatchSize`.
+#[query(name = "getCapQueueSize")]
+#[candid_method(query, rename = "getCapQueueSize")]
+fn get_cap_queue_size() -> usize {
+  CAP_QUEUE.with(|q| q.borrow().len())
+}
+
+// Every principal that has minted, with the total amount it minted.
+#[query(name = "getMinterStats")]
+#[candid_method(query, rename = "getMinterStats")]
+fn get_minter_stats() -> Vec<(Principal, Nat)> {
+  MINTERS.with(|m| {
+    let minters = m.borrow();
+    let mut stats = Vec::from_iter(minters.clone());
+    stats.sort_by_key(|a| a.0);
+    stats
+  })
+}
+
+// ICRC-3 view of the local history. Each requested range is clipped to the
+// records still held locally, see `historyCacheInfo`. A response holds at
+// most `MAX_BLOCKS_PER_RESPONSE` blocks over all ranges, the rest is cut off
+// and has to be asked for again starting after the last returned id.
+#[query]
+#[candid_method(query)]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    let mut blocks = Vec::new();
+    for arg in args {
+      let start = usize::try_from(arg.start.0).unwrap_or(usize::MAX);
+      let length = usize::try_from(arg.length.0).unwrap_or(usize::MAX);
+      let end = start.saturating_add(length);
+      for id in start.max(history.evicted)..end {
+        if blocks.len() == MAX_BLOCKS_PER_RESPONSE {
+          break;
+        }
+        match history.block(id) {
+          Some(block) => blocks.push(BlockWithId {
+            id: Nat::from(id),
+            block,
+          }),
+          None => break,
+        }
+      }
+    }
+    GetBlocksResult {
+      log_length: Nat::from(history.evicted + history.records.len()),
+      blocks,
+    }
+  })
+}
+
+// There is no archive canister, every block `icrc3_get_blocks` can return is
+// held locally. That is up to `MAX_BLOCKS_PER_RESPONSE` blocks per call.
+#[query]
+#[candid_method(query)]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+  Vec::new()
+}
+
+// Certificate over the index and hash of the latest block, so clients can
+// verify the chain served by `icrc3_get_blocks`.
+#[query]
+#[candid_method(query)]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    let tip_hash = history.tip_hash.as_ref()?;
+    icrc3::tip_certificate(history.evicted + history.records.len() - 1, tip_hash)
+  })
+}
+
+#[query(name = "getMemo")]
+#[candid_method(query, rename = "getMemo")]
+fn get_memo(index: Nat) -> Option<Vec<u8>> {
+  let index = usize::try_from(index.0).ok()?;
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    history.memos.get(&index).cloned()
+  })
+}
+
+// Mint and burn records from the local history, burned fees included, i.e.
+// every change to the total supply. Only covers what is held locally, older
+// records have to be read from CAP.
+#[query(name = "supplyEvents")]
+#[candid_method(query, rename = "supplyEvents")]
+fn supply_events(start: usize, limit: usize) -> Vec<TxRecord> {
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    let empty = VecDeque::new();
+    let indexes = |op| {
+      history
+        .op_index
+        .as_ref()
+        .and_then(|op_index| op_index.get(&TxHistory::op_key(&op)))
+        .unwrap_or(&empty)
+        .iter()
+        .peekable()
+    };
+    let mut mints = indexes(Operation::Mint);
+    let mut burns = indexes(Operation::Burn);
+    std::iter::from_fn(|| match (mints.peek(), burns.peek()) {
+      (Some(mint), Some(burn)) if burn < mint => burns.next(),
+      (Some(_), _) => mints.next(),
+      (None, _) => burns.next(),
+    })
+    .skip(start)
+    .take(limit)
+    .filter_map(|i| history.get(*i).cloned())
+    .collect()
+  })
+}
+
+#[query(name = "isBlocked")]
+#[candid_method(query, rename = "isBlocked")]
+fn is_blocked(who: Principal) -> bool {
+  BLOCKLIST.with(|b| {
+    let blocklist = b.borrow();
+    blocklist.contains(&who)
+  })
+}
+
+// Runs the checks of `transfer` without changing any state and returns what
+// the transfer would do, or the error `transfer` would return.
+#[query(name = "previewTransfer")]
+#[candid_method(query, rename = "previewTransfer")]
+fn preview_transfer(
+  from: Principal,
+  to: Principal,
+  value: Nat,
+) -> Result<TransferPreview, TxError> {
+  let fee = _check_transfer(from, to, value.clone())?;
+  let total_debit = if from == to {
+    fee.clone()
+  } else {
+    value.clone() + fee.clone()
+  };
+  let sender_balance_after = balance_of(from) - total_debit.clone();
+  let recipient_balance_after = if from == to {
+    sender_balance_after.clone()
+  } else if _is_burn_sink(to) {
+    balance_of(to)
+  } else {
+    balance_of(to) + value
+  };
+  Ok(TransferPreview {
+    fee,
+    total_debit,
+    sender_balance_after,
+    recipient_balance_after,
+  })
+}
+
+// Fee a transfer of `amount` by the caller is charged in this token,
+// computed like the transfer itself does. Fees are flat for now, so `amount`
+// doesn't change the result, and zero when a fee token is configured or the
+// caller is fee exempt.
+#[query(name = "getEffectiveFee")]
+#[candid_method(query, rename = "getEffectiveFee")]
+fn get_effective_fee(_amount: Nat) -> Nat {
+  _transfer_fee(ic::caller())
+}
+
+// Total amount a transfer from `from` to `to` would take out of the sender's
+// balance, or the error it would fail with, see `previewTransfer`. Burning
+// fees only changes where they go and a transfer to a burn sink burns
+// `value`, so this is `value` plus the transfer fee of `from`, or only the
+// fee for a self-transfer.
+#[query(name = "transferDebit")]
+#[candid_method(query, rename = "transferDebit")]
+fn transfer_debit(from: Principal, to: Principal, value: Nat) -> Result<Nat, TxError> {
+  preview_transfer(from, to, value).map(|preview| preview.total_debit)
+}
+
+/* CONTROLLER FNS */
+
+#[update(guard = "_is_auth")]
+#[candid_method(update, rename = "mint")]
+async fn mint(to: Principal, amount: Nat) -> TxReceipt {
+  _mutate(|| _mint(ic::caller(), to, amount))?.await
+}
+
+// Alias of `mint` for bridge tooling that expects DFinance-style naming. It
+// mints directly, there is no ICP block verification in this canister.
+#[update(name = "adminMint", guard = "_is_auth")]
+#[candid_method(update, rename = "adminMint")]
+async fn admin_mint(to: Principal, amount: Nat) -> TxReceipt {
+  _mutate(|| _mint(ic::caller(), to, amount))?.await
+}
+
+// Mints to several recipients in one call, one record per entry. The cycles
+// threshold and the supply cap are checked for the whole batch up front, so
+// the cap never lets only part of it through. Other checks are per entry.
+#[update(name = "mintBatch", guard = "_is_auth")]
+#[candid_method(update, rename = "mintBatch")]
+async fn mint_batch(entries: Vec<(Principal, Nat)>) -> Vec<TxReceipt> {
+  if entries.len() > MAX_BATCH_SIZE {
+    return vec![Err(TxError::Other(format!(
+      "at most {} entries per batch",
+      MAX_BATCH_SIZE
+    )))];
+  }
+  let pending = _mutate(|| -> Result<_, TxError> {
+    _check_cycles()?;
+    let minted: Nat = entries.iter().map(|(_, amount)| amount.clone()).sum();
+    if total_supply() + minted > _get_max_representable_supply() {
+      return Err(TxError::SupplyOverflow);
+    }
+    let caller = ic::caller();
+    Ok(
+      entries
+        .into_iter()
+        .map(|(to, amount)| _mint(caller, to, amount))
+        .collect::<Vec<_>>(),
+    )
+  });
+  let pending = match pending {
+    Ok(pending) => pending,
+    Err(error) => return vec![Err(error)],
+  };
+  let mut receipts = Vec::new();
+  for record in pending {
+    receipts.push(match record {
+      Ok(insert) => insert.await,
+      Err(error) => Err(error),
+    });
+  }
+  receipts
+}
+
+// Same as `mint`, with a memo of at most `MAX_MEMO_SIZE` bytes kept next to
+// the record, e.g. to tag the deposit a bridged mint comes from. There are
+// no ICP deposits here, so it takes the recipient and amount like `mint`.
+#[update(name = "mintWithMemo", guard = "_is_auth")]
+#[candid_method(update, rename = "mintWithMemo")]
+async fn mint_with_memo(to: Principal, amount: Nat, memo: Vec<u8>) -> TxReceipt {
+  if memo.len() > MAX_MEMO_SIZE {
+    return Err(TxError::Other(format!(
+      "memo is longer than {} bytes",
+      MAX_MEMO_SIZE
+    )));
+  }
+  _mutate(|| -> Result<_, TxError> {
+    let insert = _mint(ic::caller(), to, amount)?;
+    _memo_set(_last_tx_index(), memo);
+    Ok(insert)
+  })?
+  .await
+}
+
+// Mints to `to` what the emission schedule accrued since the last call, at
+// `emission_rate` tokens per whole second. The leftover fraction of a second
+// carries over to the next call.
+#[update(name = "mintAccrued", guard = "_is_auth")]
+#[candid_method(update, rename = "mintAccrued")]
+async fn mint_accrued(to: Principal) -> TxReceipt {
+  _mutate(|| -> Result<_, TxError> {
+    let now = ic::time();
+    let (seconds, amount) = STATS.with(|s| {
+      let stats = s.borrow();
+      let seconds = now.saturating_sub(stats.last_mint_time) / 1_000_000_000;
+      (seconds, stats.emission_rate.clone() * Nat::from(seconds))
+    });
+    if amount == 0 {
+      return Err(TxError::AmountTooSmall);
+    }
+    let insert = _mint(ic::caller(), to, amount)?;
+    STATS.with(|s| {
+      let mut stats = s.borrow_mut();
+      stats.last_mint_time += seconds * 1_000_000_000;
+    });
+    Ok(insert)
+  })?
+  .await
+}
+
+// Recovery of funds sent to a principal that can't move them. Moves `value`
+// without `from`'s consent and without a fee, recorded as a transfer whose
+// caller is the owner. Only available until `disableRecovery` is called.
+#[update(name = "forceTransfer", guard = "_is_auth")]
+#[candid_method(update, rename = "forceTransfer")]
+async fn force_transfer(from: Principal, to: Principal, value: Nat) -> TxReceipt {
+  _mutate(|| -> Result<_, TxError> {
+    if !STATS.with(|s| s.borrow().recovery_enabled) {
+      return Err(TxError::Other("recovery is disabled".to_string()));
+    }
+    _move(from, to, value.clone())?;
+    _history_inc();
+    Ok(add_record(
+      ic::caller(),
+      _send_operation(to, Operation::Transfer),
+      from,
+      to,
+      value,
+      Nat::from(0),
+      ic::time(),
+      TransactionStatus::Succeeded,
+    ))
+  })?
+  .await
+}
+
+// Turns `forceTransfer` off for good, there is no way to enable it again.
+#[update(name = "disableRecovery", guard = "_is_auth")]
+#[candid_method(update, rename = "disableRecovery")]
+fn disable_recovery() {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.recovery_enabled = false;
+  });
+}
+
+// Recovery tool for gaps in CAP: sends `record` to CAP again and returns the
+// id CAP assigned. The record should match the original one exactly, CAP has
+// no way to tell a re-emitted record from a new transaction. Local state and
+// history are not touched.
+#[update(name = "reemitRecord", guard = "_is_auth")]
+#[candid_method(update, rename = "reemitRecord")]
+async fn reemit_record(record: TxRecord) -> TxReceipt {
+  insert_into_cap(_to_event(record)).await
+}
+
+// Sends the records whose CAP insert failed front to back. Stops at the
+// first failure, which goes back to the front of the backlog so the order is
+// kept, and reports how many records made it.
+#[update(name = "flushCapBacklog", guard = "_is_auth")]
+#[candid_method(update, rename = "flushCapBacklog")]
+async fn flush_cap_backlog() -> Result<usize, String> {
+  _flush_cap_backlog().await
+}
+
+// Records are queued instead of inserted into CAP right away. The heartbeat
+// sends the queue once it holds `cap_batch_size` records, and at least every
+// `CAP_FLUSH_INTERVAL_SECS` while it isn't empty, so no caller waits for the
+// inserts. Zero inserts every record right away.
+#[update(name = "setCapBatchSize", guard = "_is_auth")]
+#[candid_method(update, rename = "setCapBatchSize")]
+fn set_cap_batch_size(cap_batch_size: usize) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.cap_batch_size = Some(cap_batch_size);
+  });
+}
+
+// Flushes the CAP queue when it is full or `CAP_FLUSH_INTERVAL_SECS` passed
+// since the last flush. Only one flush runs at a time.
+#[heartbeat]
+async fn heartbeat() {
+  let queued = CAP_QUEUE.with(|q| q.borrow().len());
+  if queued == 0 || _is_flushing() {
+    return;
+  }
+  let batch_size = STATS.with(|s| s.borrow().cap_batch_size.unwrap_or(0));
+  let last_flush = CAP_LAST_FLUSH.with(|l| l.get());
+  let due = ic::time().saturating_sub(last_flush) >= CAP_FLUSH_INTERVAL_SECS * 1_000_000_000;
+  if queued < batch_size && !due {
+    return;
+  }
+  match _flush_cap_queue().await {
+    Ok(flushed) => ic::print(format!("flushed {} records into cap", flushed)),
+    Err(error) => ic::print(error),
+  }
+}
+
+// Sends the queued records front to back. A failed insert moves the record
+// to the backlog, see `flushCapBacklog`, and ends the flush, the rest of the
+// queue waits for the next one.
+async fn _flush_cap_queue() -> Result<usize, String> {
+  let _flushing = Flushing::start();
+  let mut flushed = 0;
+  loop {
+    let ie = match CAP_QUEUE.with(|q| q.borrow_mut().pop_front()) {
+      Some(ie) => ie,
+      None => return Ok(flushed),
+    };
+    if let Err(error) = insert_into_cap_priv(ie).await {
+      return Err(format!("flushed {} records, then {:?}", flushed, error));
+    }
+    flushed += 1;
+    CAP_LAST_FLUSH.with(|l| l.set(ic::time()));
+  }
+}
+
+// A flush is running if one started or made progress within the last
+// `CAP_FLUSH_STALE_SECS`. A trap in one of its callbacks leaks the future
+// without dropping `Flushing`, the flag is ignored once it's that old.
+fn _is_flushing() -> bool {
+  let last_flush = CAP_LAST_FLUSH.with(|l| l.get());
+  CAP_FLUSHING.with(|f| f.get())
+    && ic::time().saturating_sub(last_flush) < CAP_FLUSH_STALE_SECS * 1_000_000_000
+}
+
+// Sets `CAP_FLUSHING` until it's dropped, also when the flush future is.
+struct Flushing;
+
+impl Flushing {
+  fn start() -> Self {
+    CAP_FLUSHING.with(|f| f.set(true));
+    CAP_LAST_FLUSH.with(|l| l.set(ic::time()));
+    Flushing
+  }
+}
+
+impl Drop for Flushing {
+  fn drop(&mut self) {
+    CAP_FLUSHING.with(|f| f.set(false));
+  }
+}
+
+async fn _flush_cap_backlog() -> Result<usize, String> {
+  let mut flushed = 0;
+  loop {
+    let next = TXLOG.with(|t| {
+      let mut tx_log = t.borrow_mut();
+      tx_log.ie_records.pop_front()
+    });
+    let ie = match next {
+      Some(ie) => ie,
+      None => return Ok(flushed),
+    };
+    if let Err(error) = insert(ie.clone()).await {
+      TXLOG.with(|t| {
+        let mut tx_log = t.borrow_mut();
+        tx_log.ie_records.push_front(ie);
+      });
+      return Err(format!(
+        "flushed {} records, then inserting into cap failed with error: {:?}",
+        flushed, error
+      ));
+    }
+    flushed += 1;
+  }
+}
+
+// Drops every expired approval, returning how many were removed.
+#[update(name = "pruneExpiredApprovals", guard = "_is_auth")]
+#[candid_method(update, rename = "pruneExpiredApprovals")]
+fn prune_expired_approvals() -> usize {
+  let now = ic::time();
+  let expired: Vec<(Principal, Principal)> = EXPIRIES.with(|e| {
+    e.borrow()
+      .iter()
+      .filter(|(_, expires_at)| **expires_at <= now)
+      .map(|(key, _)| *key)
+      .collect()
+  });
+  for (owner, spender) in expired.iter() {
+    _allowance_set(*owner, *spender, Nat::from(0));
+    _drawdown_approve(*owner, *spender, Nat::from(0));
+  }
+  expired.len()
+}
+
+// Registers `canister.method` to be notified of every transfer, mint and
+// burn, see `_notify_listeners`. Registering a canister again replaces its
+// method.
+#[update(name = "registerTransferListener", guard = "_is_auth")]
+#[candid_method(update, rename = "registerTransferListener")]
+fn register_transfer_listener(canister: Principal, method: String) -> Result<(), String> {
+  if method.is_empty() || method.len() > MAX_METHOD_NAME_SIZE {
+    return Err(format!(
+      "method name must be 1 to {} bytes",
+      MAX_METHOD_NAME_SIZE
+    ));
+  }
+  LISTENERS.with(|l| {
+    let mut listeners = l.borrow_mut();
+    if !listeners.contains_key(&canister) && listeners.len() >= MAX_LISTENERS {
+      return Err(format!("at most {} listeners", MAX_LISTENERS));
+    }
+    listeners.insert(canister, method);
+    Ok(())
+  })
+}
+
+#[update(name = "unregisterTransferListener", guard = "_is_auth")]
+#[candid_method(update, rename = "unregisterTransferListener")]
+fn unregister_transfer_listener(canister: Principal) {
+  LISTENERS.with(|l| {
+    let mut listeners = l.borrow_mut();
+    listeners.remove(&canister);
+  });
+}
+
+// Raises `decimals` to `new_decimals`, multiplying every stored amount of
+// this token by the difference in powers of ten so holdings keep their
+// value: balances, allowances, minted totals, drawdowns, snapshots, the
+// supply history, total supply, fee, minimum approval and emission rate.
+// Decreasing would lose precision and is rejected. Recorded transactions
+// keep the old scale, the rescale itself goes to CAP as a `rescaleDecimals`
+// event. Only the owner may rescale, anyone else gets `Unauthorized`.
+#[update(name = "rescaleDecimals")]
+#[candid_method(update, rename = "rescaleDecimals")]
+async fn rescale_decimals(new_decimals: u8) -> Result<(), TxError> {
+  _is_auth().map_err(|_| TxError::Unauthorized)?;
+  if new_decimals > 18 {
+    return Err(TxError::Other("decimals must be at most 18".to_string()));
+  }
+  let old_decimals = decimals();
+  if new_decimals <= old_decimals {
+    return Err(TxError::Other("decimals can only be increased".to_string()));
+  }
+  let factor = Nat(Nat::from(10).0.pow((new_decimals - old_decimals) as u32));
+  if total_supply() * factor.clone() > _get_max_representable_supply() {
+    return Err(TxError::SupplyOverflow);
+  }
+  let insert = _mutate(|| {
+    let scale = |value: &mut Nat| *value = value.clone() * factor.clone();
+    BALANCES.with(|b| b.borrow_mut().values_mut().for_each(scale));
+    ALLOWS.with(|a| {
+      let mut allowances = a.borrow_mut();
+      allowances
+        .values_mut()
+        .flat_map(|inner| inner.values_mut())
+        .for_each(scale);
+    });
+    MINTERS.with(|m| m.borrow_mut().values_mut().for_each(scale));
+    DRAWDOWNS.with(|d| {
+      for (approved, spent) in d.borrow_mut().values_mut() {
+        scale(approved);
+        scale(spent);
+      }
+    });
+    SNAPSHOTS.with(|s| {
+      let mut snapshots = s.borrow_mut();
+      snapshots.supplies.values_mut().for_each(scale);
+      snapshots
+        .balances
+        .values_mut()
+        .flat_map(|kept| kept.values_mut())
+        .for_each(scale);
+    });
+    SUPPLY_HISTORY.with(|h| {
+      for (_, supply) in h.borrow_mut().iter_mut() {
+        scale(supply);
+      }
+    });
+    STATS.with(|s| {
+      let mut stats = s.borrow_mut();
+      stats.decimals = new_decimals;
+      scale(&mut stats.total_supply);
+      scale(&mut stats.fee);
+      scale(&mut stats.min_approval);
+      scale(&mut stats.emission_rate);
+    });
+    _supply_point();
+    _history_inc();
+    _cap_insert(IndefiniteEvent {
+      caller: ic::caller(),
+      operation: "rescaleDecimals".to_string(),
+      details: vec![
+        ("from".to_string(), DetailValue::U64(old_decimals as u64)),
+        ("to".to_string(), DetailValue::U64(new_decimals as u64)),
+      ],
+    })
+  });
+  if let Some(insert) = insert {
+    if let Err(error) = insert.await {
+      ic::print(format!("rescale queued for cap: {:?}", error));
+    }
+  }
+  Ok(())
+}
+
+// Removes zero balances left by older versions, returning how many.
+#[update(name = "compactBalances", guard = "_is_auth")]
+#[candid_method(update, rename = "compactBalances")]
+fn compact_balances() -> usize {
+  _compact_balances()
+}
+
+// Caps the number of holders, `None` lifts the cap. Transfers, approvals
+// and mints that would add a holder beyond it, `fee_to` receiving a fee
+// included, fail with `MaxHoldersReached`. Lowering it below the current
+// count only stops new holders.
+#[update(name = "setMaxHolders", guard = "_is_auth")]
+#[candid_method(update, rename = "setMaxHolders")]
+fn set_max_holders(max_holders: Option<usize>) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.max_holders = max_holders;
+  });
+}
+
+// Logos longer than `max_logo_size` bytes are rejected by `setLogo`, the
+// current logo is kept even if it's over the new limit.
+#[update(name = "setMaxLogoSize", guard = "_is_auth")]
+#[candid_method(update, rename = "setMaxLogoSize")]
+fn set_max_logo_size(max_logo_size: usize) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.max_logo_size = max_logo_size;
+  });
+}
+
+#[update(name = "setMaxSupplyPoints", guard = "_is_auth")]
+#[candid_method(update, rename = "setMaxSupplyPoints")]
+fn set_max_supply_points(max_supply_points: usize) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.max_supply_points = Some(max_supply_points);
+  });
+  SUPPLY_HISTORY.with(|h| {
+    let mut supply_history = h.borrow_mut();
+    while supply_history.len() > max_supply_points {
+      supply_history.pop_front();
+    }
+  });
+}
+
+#[update(name = "setMinCyclesThreshold", guard = "_is_auth")]
+#[candid_method(update, rename = "setMinCyclesThreshold")]
+fn set_min_cycles_threshold(min_cycles_threshold: u64) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.min_cycles_threshold = min_cycles_threshold;
+  });
+}
+
+#[update(name = "setTransferCooldown", guard = "_is_auth")]
+#[candid_method(update, rename = "setTransferCooldown")]
+fn set_transfer_cooldown(transfer_cooldown_secs: u64) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.transfer_cooldown_secs = transfer_cooldown_secs;
+  });
+}
+
+// Takes a snapshot of the balances and total supply for `balanceOfAt` and
+// `totalSupplyAt`, returns the snapshot id. Balances aren't copied, see
+// `Snapshots`. Ids only ever grow, the oldest snapshots are dropped once
+// there are more than `max_snapshots`.
+#[update(guard = "_is_auth")]
+#[candid_method(update)]
+fn snapshot() -> u64 {
+  let (snapshot_id, total_supply, max_snapshots) = STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    let snapshot_id = stats.snapshot_counter;
+    stats.snapshot_counter += 1;
+    (snapshot_id, stats.total_supply.clone(), stats.max_snapshots)
+  });
+  SNAPSHOTS.with(|s| {
+    let mut snapshots = s.borrow_mut();
+    snapshots.supplies.insert(snapshot_id, total_supply);
+    snapshots.evict(max_snapshots);
+  });
+  snapshot_id
+}
+
+// Mints that would take the total supply above `max_representable_supply`
+// fail with `SupplyOverflow`, by default anything beyond a u128. Lowering it
+// below the current supply only stops further mints.
+#[update(name = "setMaxRepresentableSupply", guard = "_is_auth")]
+#[candid_method(update, rename = "setMaxRepresentableSupply")]
+fn set_max_representable_supply(max_representable_supply: Nat) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.max_representable_supply = Some(max_representable_supply);
+  });
+}
+
+#[update(name = "setMaxSnapshots", guard = "_is_auth")]
+#[candid_method(update, rename = "setMaxSnapshots")]
+fn set_max_snapshots(max_snapshots: usize) {
+  assert!(max_snapshots > 0, "max_snapshots must be at least 1");
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.max_snapshots = max_snapshots;
+  });
+  SNAPSHOTS.with(|s| {
+    let mut snapshots = s.borrow_mut();
+    snapshots.evict(max_snapshots);
+  });
+}
+
+// Re-points the token at another CAP instance. The handshake completes
+// asynchronously, records inserted before it does still go to the old
+// root. `pre_upgrade` archives whichever env is current.
+#[update(name = "setCapRoot", guard = "_is_auth")]
+#[candid_method(update, rename = "setCapRoot")]
+fn set_cap_root(cap: Principal) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.cap = Some(cap);
+  });
+  handshake(1_000_000_000_000, Some(cap));
+}
+
+// Overwrites `history_size`, for when it drifted from the number of records
+// in CAP. Doesn't touch the local history or `tx_counter`.
+#[update(name = "reconcileHistorySize", guard = "_is_auth")]
+#[candid_method(update, rename = "reconcileHistorySize")]
+fn reconcile_history_size(value: usize) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.history_size = value;
+  });
+}
+
+#[update(name = "setName", guard = "_is_auth")]
+#[candid_method(update, rename = "setName")]
+fn set_name(name: String) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.name = name;
+  });
+}
+
+#[update(name = "setLogo", guard = "_is_auth")]
+#[candid_method(update, rename = "setLogo")]
+fn set_logo(logo: String) {
+  _check_logo_size(&logo);
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.logo = logo;
+  });
+}
+
+// Sets the tokens emitted per second, see `mintAccrued`. Emission restarts
+// from now, anything accrued at the previous rate and not minted is dropped.
+#[update(name = "setEmissionRate", guard = "_is_auth")]
+#[candid_method(update, rename = "setEmissionRate")]
+fn set_emission_rate(tokens_per_second: Nat) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.emission_rate = tokens_per_second;
+    stats.last_mint_time = ic::time();
+  });
+}
+
+#[update(name = "setFee", guard = "_is_auth")]
+#[candid_method(update, rename = "setFee")]
+fn set_fee(fee: Nat) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.fee = fee;
+  });
+}
+
+#[update(name = "setFeeToken", guard = "_is_auth")]
+#[candid_method(update, rename = "setFeeToken")]
+fn set_fee_token(fee_token: Option<Principal>, fee_token_amount: Nat) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.fee_token = fee_token;
+    stats.fee_token_amount = fee_token_amount;
+  });
+}
+
+#[update(name = "setFeeTo", guard = "_is_auth")]
+#[candid_method(update, rename = "setFeeTo")]
+fn set_fee_to(fee_to: Principal) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.fee_to = fee_to;
+  });
+}
+
+#[update(name = "blockAccount", guard = "_is_auth")]
+#[candid_method(update, rename = "blockAccount")]
+fn block_account(who: Principal) {
+  BLOCKLIST.with(|b| {
+    let mut blocklist = b.borrow_mut();
+    blocklist.insert(who);
+  });
+}
+
+#[update(name = "unblockAccount", guard = "_is_auth")]
+#[candid_method(update, rename = "unblockAccount")]
+fn unblock_account(who: Principal) {
+  BLOCKLIST.with(|b| {
+    let mut blocklist = b.borrow_mut();
+    blocklist.remove(&who);
+  });
+}
+
+// Replaces the set of principals, e.g. the treasury, whose balances don't
+// count towards `circulatingSupply`.
+#[update(name = "setExcludedFromCirculating", guard = "_is_auth")]
+#[candid_method(update, rename = "setExcludedFromCirculating")]
+fn set_excluded_from_circulating(excluded: Vec<Principal>) {
+  EXCLUDED.with(|e| {
+    let mut set = e.borrow_mut();
+    *set = HashSet::from_iter(excluded);
+  });
+}
+
+// Transfers, including `transferFrom` out of an exempt account, and
+// approvals by fee exempt principals are free, also of the fee token fee.
+#[update(name = "addFeeExempt", guard = "_is_auth")]
+#[candid_method(update, rename = "addFeeExempt")]
+fn add_fee_exempt(who: Principal) {
+  FEE_EXEMPT.with(|f| {
+    let mut fee_exempt = f.borrow_mut();
+    fee_exempt.insert(who);
+  });
+}
+
+#[update(name = "removeFeeExempt", guard = "_is_auth")]
+#[candid_method(update, rename = "removeFeeExempt")]
+fn remove_fee_exempt(who: Principal) {
+  FEE_EXEMPT.with(|f| {
+    let mut fee_exempt = f.borrow_mut();
+    fee_exempt.remove(&who);
+  });
+}
+
+// Replaces the set of burn sinks. Transfers to a sink, including
+// `transferFrom` and `forceTransfer`, lower the total supply instead of
+// crediting the sink and are recorded as burns.
+#[update(name = "setBurnSinks", guard = "_is_auth")]
+#[candid_method(update, rename = "setBurnSinks")]
+fn set_burn_sinks(burn_sinks: Vec<Principal>) {
+  BURN_SINKS.with(|b| {
+    let mut set = b.borrow_mut();
+    *set = HashSet::from_iter(burn_sinks);
+  });
+}
+
+#[update(name = "setAllowZeroTransfers", guard = "_is_auth")]
+#[candid_method(update, rename = "setAllowZeroTransfers")]
+fn set_allow_zero_transfers(allow_zero_transfers: bool) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.allow_zero_transfers = Some(allow_zero_transfers);
+  });
+}
+
+#[update(name = "setBurnFees", guard = "_is_auth")]
+#[candid_method(update, rename = "setBurnFees")]
+fn set_burn_fees(burn_fees: bool) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.burn_fees = burn_fees;
+  });
+}
+
+// `Account` also sets `fee_to`, `Burn` leaves it as is for switching back
+// with `setBurnFees`.
+#[update(name = "setFeeDestination", guard = "_is_auth")]
+#[candid_method(update, rename = "setFeeDestination")]
+fn set_fee_destination(fee_destination: FeeDestination) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    match fee_destination {
+      FeeDestination::Account(fee_to) => {
+        stats.fee_to = fee_to;
+        stats.burn_fees = false;
+      }
+      FeeDestination::Burn => stats.burn_fees = true,
+    }
+  });
+}
+
+#[update(name = "setMinApproval", guard = "_is_auth")]
+#[candid_method(update, rename = "setMinApproval")]
+fn set_min_approval(min_approval: Nat) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.min_approval = min_approval;
+  });
+}
+
+#[update(name = "setRateLimit", guard = "_is_auth")]
+#[candid_method(update, rename = "setRateLimit")]
+fn set_rate_limit(rate_limit: Option<(usize, u64)>) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.rate_limit = rate_limit;
+  });
+}
+
+// Makes the next upgrade run `_repair_state`, the flag is cleared once it ran.
+#[update(name = "setRepairOnUpgrade", guard = "_is_auth")]
+#[candid_method(update, rename = "setRepairOnUpgrade")]
+fn set_repair_on_upgrade(repair_on_upgrade: bool) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.repair_on_upgrade = repair_on_upgrade;
+  });
+}
+
+// Rejects transfers, approvals and mints to the anonymous principal, see
+// `_check_recipient`.
+#[update(name = "setRejectAnonymous", guard = "_is_auth")]
+#[candid_method(update, rename = "setRejectAnonymous")]
+fn set_reject_anonymous(reject_anonymous: bool) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.reject_anonymous = Some(reject_anonymous);
+  });
+}
+
+#[update(name = "setRequireCapSuccess", guard = "_is_auth")]
+#[candid_method(update, rename = "setRequireCapSuccess")]
+fn set_require_cap_success(require_cap_success: bool) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.require_cap_success = require_cap_success;
+  });
+}
+
+#[update(name = "setSaleWindow", guard = "_is_auth")]
+#[candid_method(update, rename = "setSaleWindow")]
+fn set_sale_window(sale_start: Option<u64>, sale_end: Option<u64>) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.sale_start = sale_start;
+    stats.sale_end = sale_end;
+  });
+}
+
+// First step of handing over ownership, `owner` only becomes the owner once
+// it calls `acceptOwnership`.
+#[update(name = "setOwner", guard = "_is_auth")]
+#[candid_method(update, rename = "setOwner")]
+fn set_owner(owner: Principal) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.pending_owner = Some(owner);
+  });
+}
+
+#[update(name = "cancelOwnershipTransfer", guard = "_is_auth")]
+#[candid_method(update, rename = "cancelOwnershipTransfer")]
+fn cancel_ownership_transfer() {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.pending_owner = None;
+  });
+}
+
+#[update(name = "acceptOwnership")]
+#[candid_method(update, rename = "acceptOwnership")]
+fn accept_ownership() -> Result<(), String> {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    if stats.pending_owner != Some(ic::caller()) {
+      return Err("Error: caller is not the pending owner".to_string());
+    }
+    stats.owner = ic::caller();
+    stats.pending_owner = None;
+    Ok(())
+  })
+}
+
+/* INTERNAL FNS */
+
+// TODO: use controllers for ownership
+// this will require the canister to be a controller of itself (like dip721)
+fn _is_auth() -> Result<(), String> {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    if ic::caller() == stats.owner {
+      Ok(())
+    } else {
+      Err("Error: Unauthorized principal ID".to_string())
+    }
+  })
+}
+
+// Validation shared by `transfer` and `previewTransfer`, returns the fee.
+fn _check_transfer(from: Principal, to: Principal, value: Nat) -> Result<Nat, TxError> {
+  _check_blocked(&[from, to])?;
+  _check_recipient(to)?;
+  _check_zero_value(&value)?;
+  _check_sale_window(from)?;
+  _check_rate_limit(from)?;
+  _check_cooldown(from)?;
+  _check_cycles()?;
+  let fee = _transfer_fee(from);
+  // a self-transfer moves nothing and only costs the fee
+  let required = if from == to {
+    std::cmp::max(value.clone(), fee.clone())
+  } else {
+    value.clone() + fee.clone()
+  };
+  let from_balance = balance_of(from);
+  if from_balance < required {
+    return Err(TxError::InsufficientBalance);
+  }
+  _check_new_holders(from, to, &value, &fee, from_balance)?;
+  Ok(fee)
+}
+
+fn _send(
+  from: Principal,
+  to: Principal,
+  value: Nat,
+) -> Result<impl Future<Output = TxReceipt>, TxError> {
+  let fee = _apply_send(from, to, value.clone())?;
+  Ok(add_record(
+    from,
+    _send_operation(to, Operation::Transfer),
+    from,
+    to,
+    value,
+    fee,
+    ic::time(),
+    TransactionStatus::Succeeded,
+  ))
+}
+
+// Balance changes of a transfer, returning the fee charged.
+fn _apply_send(from: Principal, to: Principal, value: Nat) -> Result<Nat, TxError> {
+  let fee = _check_transfer(from, to, value.clone())?;
+  _charge_fee(from, fee.clone())?;
+  _move(from, to, value)?;
+  _rate_limit_hit(from);
+  _cooldown_start(from);
+  _history_inc();
+  Ok(fee)
+}
+
+// What `transferAll` sends: the balance of `from` minus the transfer fee.
+fn _transfer_all_value(from: Principal) -> Result<Nat, TxError> {
+  _try_sub(balance_of(from), _transfer_fee(from)).ok_or(TxError::InsufficientBalance)
+}
+
+// Moves `value` from `from` to `to`, or burns it if `to` is a burn sink.
+fn _move(from: Principal, to: Principal, value: Nat) -> Result<(), TxError> {
+  if _is_burn_sink(to) {
+    _burn(from, value)
+  } else {
+    _transfer(from, to, value)
+  }
+}
+
+fn _is_burn_sink(who: Principal) -> bool {
+  BURN_SINKS.with(|b| b.borrow().contains(&who))
+}
+
+// Sends to a burn sink destroy the value, so they are recorded as burns.
+fn _send_operation(to: Principal, op: Operation) -> Operation {
+  if _is_burn_sink(to) {
+    Operation::Burn
+  } else {
+    op
+  }
+}
+
+// What a strict transfer is about to change, captured before its CAP insert
+// so the rollback after the await doesn't read settings changed meanwhile.
+struct SendUndo {
+  from: Principal,
+  to: Principal,
+  value: Nat,
+  fee: Nat,
+  fee_to: Principal,
+  burn_fees: bool,
+  burn_value: bool,
+  time: u64,
+  last_transfer: Option<u64>,
+}
+
+fn _send_undo(from: Principal, to: Principal, value: Nat) -> SendUndo {
+  let (fee_to, burn_fees) = STATS.with(|s| {
+    let stats = s.borrow();
+    (stats.fee_to, stats.burn_fees)
+  });
+  SendUndo {
+    from,
+    to,
+    value,
+    fee: Nat::from(0),
+    fee_to,
+    burn_fees,
+    burn_value: _is_burn_sink(to),
+    time: ic::time(),
+    last_transfer: LAST_TRANSFER.with(|l| l.borrow().get(&from).copied()),
+  }
+}
+
+// Undoes `_apply_send`, failing without changes if `to` or `fee_to` no
+// longer hold what they received. The records already stored stay as they
+// are, every move back gets its own local record from the token canister: a
+// transfer back, or a mint where the value or the fee was burned. Like the
+// record they compensate, these never go to CAP.
+fn _rollback_send(undo: SendUndo) -> Result<(), TxError> {
+  let SendUndo {
+    from,
+    to,
+    value,
+    fee,
+    fee_to,
+    burn_fees,
+    burn_value,
+    time,
+    last_transfer,
+  } = undo;
+  if burn_value {
+    _unburn(from, value.clone());
+  } else {
+    _transfer(to, from, value.clone())?;
+  }
+  let fee_back = if fee == 0 || burn_fees {
+    Ok(())
+  } else {
+    _transfer(fee_to, from, fee.clone())
+  };
+  if let Err(error) = fee_back {
+    if burn_value {
+      _burn(from, value)?;
+    } else {
+      _transfer(from, to, value)?;
+    }
+    return Err(error);
+  }
+  if fee != 0 && burn_fees {
+    _unburn(from, fee.clone());
+  }
+  TRANSFER_TIMES.with(|t| {
+    let mut times = t.borrow_mut();
+    if let Some(at) = times.get_mut(&from) {
+      if at.back() == Some(&time) {
+        at.pop_back();
+      }
+    }
+  });
+  LAST_TRANSFER.with(|l| {
+    let mut last_transfers = l.borrow_mut();
+    if last_transfers.get(&from) == Some(&time) {
+      match last_transfer {
+        Some(last) => last_transfers.insert(from, last),
+        None => last_transfers.remove(&from),
+      };
+    }
+  });
+  let canister = ic::id();
+  let mut moves = vec![(burn_value, to, value)];
+  if fee != 0 {
+    moves.push((burn_fees, fee_to, fee));
+  }
+  for (burned, holder, amount) in moves {
+    _history_inc();
+    let (op, from_holder) = if burned {
+      (Operation::Mint, canister)
+    } else {
+      (Operation::Transfer, holder)
+    };
+    _notify_listeners(from_holder, from, amount.clone(), op.clone());
+    _record(
+      canister,
+      op,
+      from_holder,
+      from,
+      amount,
+      Nat::from(0),
+      ic::time(),
+      TransactionStatus::Succeeded,
+    );
+  }
+  Ok(())
+}
+
+// Gives `fee` charged to `user` back, from `fee_to` or by minting it again
+// if it was burned, as captured when it was charged. `None` if there is
+// nothing to refund or `fee_to` no longer holds it.
+fn _refund_fee(
+  user: Principal,
+  fee: Nat,
+  fee_to: Principal,
+  burn_fees: bool,
+) -> Option<impl Future<Output = TxReceipt>> {
+  if fee == 0 {
+    return None;
+  }
+  let canister = ic::id();
+  let (op, from) = if burn_fees {
+    _unburn(user, fee.clone());
+    (Operation::Mint, canister)
+  } else {
+    _transfer(fee_to, user, fee.clone()).ok()?;
+    (Operation::Transfer, fee_to)
+  };
+  _history_inc();
+  Some(add_record(
+    canister,
+    op,
+    from,
+    user,
+    fee,
+    Nat::from(0),
+    ic::time(),
+    TransactionStatus::Succeeded,
+  ))
+}
+
+// Gives back `amount` burned from `to`'s balance.
+fn _unburn(to: Principal, amount: Nat) {
+  _balance_ins(to, balance_of(to) + amount.clone());
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.total_supply += amount;
+  });
+  _supply_point();
+}
+
+fn _send_from(
+  spender: Principal,
+  from: Principal,
+  to: Principal,
+  value: Nat,
+) -> Result<impl Future<Output = TxReceipt>, TxError> {
+  _check_blocked(&[spender, from, to])?;
+  _check_recipient(to)?;
+  _check_zero_value(&value)?;
+  _check_sale_window(from)?;
+  _check_rate_limit(from)?;
+  _check_cooldown(spender)?;
+  _check_cycles()?;
+  let from_allowance = allowance(from, spender);
+  let fee = _transfer_fee(from);
+  let spent = _allowance_spent(from, to, &value, &fee);
+  let from_allowance_new = match _try_sub(from_allowance, spent.clone()) {
+    Some(allowance) => allowance,
+    None => return Err(TxError::InsufficientAllowance),
+  };
+  // a self-transfer moves nothing and only costs the fee, as in
+  // `_check_transfer`
+  let required = if from == to {
+    std::cmp::max(value.clone(), fee.clone())
+  } else {
+    value.clone() + fee.clone()
+  };
+  let from_balance = balance_of(from);
+  if from_balance < required {
+    return Err(TxError::InsufficientBalance);
+  }
+  _check_new_holders(from, to, &value, &fee, from_balance)?;
+  _charge_fee(from, fee.clone())?;
+  _move(from, to, value.clone())?;
+  _allowance_set(from, spender, from_allowance_new);
+  _drawdown_spend(from, spender, spent);
+  _rate_limit_hit(from);
+  _cooldown_start(spender);
+  _history_inc();
+  Ok(add_record(
+    spender,
+    _send_operation(to, Operation::TransferFrom),
+    from,
+    to,
+    value,
+    fee,
+    ic::time(),
+    TransactionStatus::Succeeded,
+  ))
+}
+
+// What a `transferFrom` takes out of the allowance: `value` and the fee, or
+// only the fee for a self-transfer since nothing else leaves `from`.
+fn _allowance_spent(from: Principal, to: Principal, value: &Nat, fee: &Nat) -> Nat {
+  if from == to {
+    fee.clone()
+  } else {
+    value.clone() + fee.clone()
+  }
+}
+
+fn _approve(
+  owner: Principal,
+  spender: Principal,
+  value: Nat,
+  expires_at: Option<u64>,
+) -> Result<impl Future<Output = TxReceipt>, TxError> {
+  _check_blocked(&[owner, spender])?;
+  _check_recipient(spender)?;
+  if value != 0 && value < _get_min_approval() {
+    return Err(TxError::AmountTooSmall);
+  }
+  let fee = _get_fee(owner);
+  let owner_balance = balance_of(owner);
+  if owner_balance < fee.clone() {
+    return Err(TxError::InsufficientBalance);
+  }
+  // only the fee moves, like for a self-transfer
+  _check_new_holders(owner, owner, &value, &fee, owner_balance)?;
+  _charge_fee(owner, fee.clone())?;
+  _allowance_set(owner, spender, value.clone());
+  _expiry_set(owner, spender, expires_at);
+  _drawdown_approve(owner, spender, value.clone());
+  _history_inc();
+  Ok(add_record(
+    owner,
+    Operation::Approve,
+    owner,
+    spender,
+    value,
+    fee,
+    ic::time(),
+    TransactionStatus::Succeeded,
+  ))
+}
+
+// Canister ids are opaque ids, which end with the 0x01 class byte.
+fn _is_canister(id: Principal) -> bool {
+  id.as_slice().last() == Some(&0x01)
+}
+
+fn _mint(
+  caller: Principal,
+  to: Principal,
+  amount: Nat,
+) -> Result<impl Future<Output = TxReceipt>, TxError> {
+  _check_cycles()?;
+  _check_recipient(to)?;
+  _check_new_holder(&[(to, &amount)], false)?;
+  let max_supply = _get_max_representable_supply();
+  if total_supply() + amount.clone() > max_supply {
+    return Err(TxError::SupplyOverflow);
+  }
+  let to_balance = balance_of(to);
+  _balance_ins(to, to_balance + amount.clone());
+  MINTERS.with(|m| {
+    let mut minters = m.borrow_mut();
+    *minters.entry(caller).or_insert_with(|| Nat::from(0)) += amount.clone();
+  });
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.total_supply += amount.clone();
+  });
+  _supply_point();
+  _history_inc();
+  Ok(add_record(
+    caller,
+    Operation::Mint,
+    caller,
+    to,
+    amount,
+    Nat::from(0),
+    ic::time(),
+    TransactionStatus::Succeeded,
+  ))
+}
+
+// Runs the state changes of an update method. The closure is synchronous so
+// no other message can interleave with it, but the CAP insert awaited after
+// it can. Traps while one of the caller's earlier calls is still suspended
+// on that insert, so a caller's changes are applied in the order they got
+// recorded.
+fn _mutate<T>(f: impl FnOnce() -> T) -> T {
+  if _is_recording(ic::caller()) {
+    ic::trap("reentrant state mutation: a previous call is still being recorded");
+  }
+  f()
+}
+
+fn _is_recording(caller: Principal) -> bool {
+  RECORDING.with(|r| r.borrow().contains_key(&caller))
+}
+
+// Marks `caller` as waiting on a CAP insert until it's dropped, which also
+// happens when the future holding it is dropped before it completes.
+struct Recording(Principal);
+
+impl Recording {
+  fn start(caller: Principal) -> Self {
+    RECORDING.with(|r| *r.borrow_mut().entry(caller).or_insert(0) += 1);
+    Recording(caller)
+  }
+}
+
+impl Drop for Recording {
+  fn drop(&mut self) {
+    RECORDING.with(|r| {
+      let mut recording = r.borrow_mut();
+      if let Some(count) = recording.get_mut(&self.0) {
+        *count -= 1;
+        if *count == 0 {
+          recording.remove(&self.0);
+        }
+      }
+    });
+  }
+}
+
+// Transfers out of any account but the owner's are only allowed inside the
+// sale window, an unset bound leaves that side of the window open.
+fn _check_sale_window(from: Principal) -> Result<(), TxError> {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    let now = ic::time();
+    let started = stats.sale_start.is_none_or(|start| now >= start);
+    let ended = stats.sale_end.is_some_and(|end| now >= end);
+    if from == stats.owner || (started && !ended) {
+      Ok(())
+    } else {
+      Err(TxError::Other("outside sale window".to_string()))
+    }
+  })
+}
+
+// The `(max transfers, window)` limit applying to `user`, the owner is exempt.
+fn _rate_limit_for(user: Principal) -> Option<(usize, u64)> {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    if user == stats.owner {
+      None
+    } else {
+      stats.rate_limit
+    }
+  })
+}
+
+fn _rate_limit_remaining(user: Principal) -> Option<usize> {
+  let (max, window) = _rate_limit_for(user)?;
+  let now = ic::time();
+  let used = TRANSFER_TIMES.with(|t| {
+    let times = t.borrow();
+    times.get(&user).map_or(0, |times| {
+      times
+        .iter()
+        .filter(|at| now.saturating_sub(**at) < window)
+        .count()
+    })
+  });
+  Some(max.saturating_sub(used))
+}
+
+fn _check_rate_limit(user: Principal) -> Result<(), TxError> {
+  match _rate_limit_remaining(user) {
+    Some(0) => Err(TxError::Other("rate limited".to_string())),
+    _ => Ok(()),
+  }
+}
+
+// Records a transfer by `user` and drops the user's times that left the
+// window, other users' are dropped by `_compact_balances`. Times are only
+// kept in memory, an upgrade starts all windows over.
+fn _rate_limit_hit(user: Principal) {
+  let window = match _rate_limit_for(user) {
+    Some((_, window)) => window,
+    None => return,
+  };
+  let now = ic::time();
+  TRANSFER_TIMES.with(|t| {
+    let mut times = t.borrow_mut();
+    let at = times.entry(user).or_default();
+    _drop_times_before(at, now, window);
+    at.push_back(now);
+  });
+}
+
+fn _drop_times_before(at: &mut VecDeque<u64>, now: u64, window: u64) {
+  while at
+    .front()
+    .is_some_and(|first| now.saturating_sub(*first) >= window)
+  {
+    at.pop_front();
+  }
+}
+
+fn _cooldown_nanos() -> u64 {
+  let secs = STATS.with(|s| s.borrow().transfer_cooldown_secs);
+  secs.saturating_mul(1_000_000_000)
+}
+
+// Callers of `transfer` and `transferFrom` have to wait
+// `transfer_cooldown_secs` between transfers.
+fn _check_cooldown(caller: Principal) -> Result<(), TxError> {
+  let cooldown = _cooldown_nanos();
+  if cooldown == 0 {
+    return Ok(());
+  }
+  let last = LAST_TRANSFER.with(|l| l.borrow().get(&caller).copied());
+  match last {
+    Some(last) if ic::time().saturating_sub(last) < cooldown => Err(TxError::Cooldown),
+    _ => Ok(()),
+  }
+}
+
+fn _cooldown_start(caller: Principal) {
+  if _cooldown_nanos() == 0 {
+    return;
+  }
+  LAST_TRANSFER.with(|l| {
+    let mut last_transfer = l.borrow_mut();
+    last_transfer.insert(caller, ic::time());
+  });
+}
+
+// Transfers and mints stop below `min_cycles_threshold`, before CAP inserts
+// would start failing.
+fn _check_cycles() -> Result<(), TxError> {
+  let threshold = STATS.with(|s| s.borrow().min_cycles_threshold);
+  if ic::balance() < threshold {
+    Err(TxError::LowCycles)
+  } else {
+    Ok(())
+  }
+}
+
+// Turns snapshot copies into `Snapshots`, keeping a balance only where it
+// differs from the next later snapshot, or from `current` for the latest.
+fn _migrate_snapshots(copies: SnapshotCopies, current: &Balances) -> Snapshots {
+  let zero = Nat::from(0);
+  let mut snapshots = Snapshots::default();
+  let mut later = current.clone();
+  for (snapshot_id, (total_supply, balances)) in copies.into_iter().rev() {
+    snapshots.supplies.insert(snapshot_id, total_supply);
+    let accounts: HashSet<Principal> = balances.keys().chain(later.keys()).copied().collect();
+    for account in accounts {
+      let balance = balances.get(&account).unwrap_or(&zero);
+      if later.get(&account).unwrap_or(&zero) != balance {
+        snapshots
+          .balances
+          .entry(account)
+          .or_default()
+          .insert(snapshot_id, balance.clone());
+      }
+    }
+    later = balances;
+  }
+  snapshots
+}
+
+fn _check_logo_size(logo: &str) {
+  let max_logo_size = STATS.with(|s| s.borrow().max_logo_size);
+  assert!(
+    logo.len() <= max_logo_size,
+    "logo must be at most {} bytes",
+    max_logo_size
+  );
+}
+
+// Nobody controls the anonymous principal, tokens sent to it are lost.
+// Only rejected when `reject_anonymous` is set.
+fn _check_recipient(to: Principal) -> Result<(), TxError> {
+  let reject_anonymous = STATS.with(|s| s.borrow().reject_anonymous.unwrap_or(false));
+  if reject_anonymous && to == Principal::anonymous() {
+    Err(TxError::ErrorTo)
+  } else {
+    Ok(())
+  }
+}
+
+// Zero value transfers only cost the fee and add a record, they are rejected
+// unless `allow_zero_transfers` is set. Approving zero still revokes.
+fn _check_zero_value(value: &Nat) -> Result<(), TxError> {
+  let allow_zero_transfers = STATS.with(|s| s.borrow().allow_zero_transfers.unwrap_or(false));
+  if *value == 0 && !allow_zero_transfers {
+    Err(TxError::AmountTooSmall)
+  } else {
+    Ok(())
+  }
+}
+
+// Crediting every `(account, amount)` may not add holders beyond
+// `max_holders`, a sender whose balance is emptied by the same transfer
+// makes room for one.
+fn _check_new_holder(credits: &[(Principal, &Nat)], sender_leaves: bool) -> Result<(), TxError> {
+  let max_holders = match STATS.with(|s| s.borrow().max_holders) {
+    Some(max_holders) => max_holders,
+    None => return Ok(()),
+  };
+  BALANCES.with(|b| {
+    let balances = b.borrow();
+    let new_holders: HashSet<Principal> = credits
+      .iter()
+      .filter(|(account, amount)| **amount != 0 && !balances.contains_key(account))
+      .map(|(account, _)| *account)
+      .collect();
+    let leaving = usize::from(sender_leaves);
+    if new_holders.len() <= leaving || balances.len() + new_holders.len() - leaving <= max_holders {
+      Ok(())
+    } else {
+      Err(TxError::MaxHoldersReached)
+    }
+  })
+}
+
+// Holder check of a transfer out of a balance of `from_balance`: `to`
+// receives `value` unless it is `from` or a burn sink, and `fee_to` receives
+// the fee unless fees are burned.
+fn _check_new_holders(
+  from: Principal,
+  to: Principal,
+  value: &Nat,
+  fee: &Nat,
+  from_balance: Nat,
+) -> Result<(), TxError> {
+  let zero = Nat::from(0);
+  let (fee_to, burn_fees) = STATS.with(|s| {
+    let stats = s.borrow();
+    (stats.fee_to, stats.burn_fees)
+  });
+  let debit = if from == to {
+    fee.clone()
+  } else {
+    value.clone() + fee.clone()
+  };
+  let received = if from == to || _is_burn_sink(to) {
+    &zero
+  } else {
+    value
+  };
+  let fee_received = if burn_fees { &zero } else { fee };
+  _check_new_holder(
+    &[(to, received), (fee_to, fee_received)],
+    from_balance == debit,
+  )
+}
+
+fn _check_blocked(accounts: &[Principal]) -> Result<(), TxError> {
+  if accounts.iter().any(|account| is_blocked(*account)) {
+    Err(TxError::AccountBlocked)
+  } else {
+    Ok(())
+  }
+}
+
+// Holders are the principals with a balance, so a zero balance is never
+// stored.
+fn _balance_ins(from: Principal, value: Nat) {
+  _snapshot_balance(from);
+  BALANCES.with(|b| {
+    let mut balances = b.borrow_mut();
+    if value != 0 {
+      balances.insert(from, value);
+    } else {
+      balances.remove(&from);
+    }
+  });
+}
+
+fn _balance_rem(from: Principal) {
+  _snapshot_balance(from);
+  BALANCES.with(|b| {
+    let mut balances = b.borrow_mut();
+    balances.remove(&from);
+  });
+}
+
+// Called before `account`'s balance changes, see `Snapshots`.
+fn _snapshot_balance(account: Principal) {
+  SNAPSHOTS.with(|s| {
+    let mut snapshots = s.borrow_mut();
+    snapshots.record(account, || balance_of(account));
+  });
+}
+
+// Checked `Nat` subtraction, `Nat`'s `Sub` impl traps on underflow.
+fn _try_sub(a: Nat, b: Nat) -> Option<Nat> {
+  if a < b {
+    None
+  } else {
+    Some(a - b)
+  }
+}
+
+// Starts tracking the draw-down of a new approval, a zero approval stops it.
+fn _drawdown_approve(owner: Principal, spender: Principal, value: Nat) {
+  DRAWDOWNS.with(|d| {
+    let mut drawdowns = d.borrow_mut();
+    if value == 0 {
+      drawdowns.remove(&(owner, spender));
+    } else {
+      drawdowns.insert((owner, spender), (value, Nat::from(0)));
+    }
+  });
+}
+
+fn _drawdown_spend(owner: Principal, spender: Principal, amount: Nat) {
+  DRAWDOWNS.with(|d| {
+    let mut drawdowns = d.borrow_mut();
+    if let Some((_, spent)) = drawdowns.get_mut(&(owner, spender)) {
+      *spent += amount;
+    }
+  });
+}
+
+// Sets the allowance of `spender` over `owner`'s balance, dropping empty entries.
+// The expiry is kept unless the allowance is dropped.
+fn _allowance_set(owner: Principal, spender: Principal, value: Nat) {
+  if value == 0 {
+    _expiry_set(owner, spender, None);
+  }
+  SPENDERS.with(|s| {
+    let mut spenders = s.borrow_mut();
+    if value != 0 {
+      spenders.entry(spender).or_default().insert(owner);
+    } else if let Some(owners) = spenders.get_mut(&spender) {
+      owners.remove(&owner);
+      if owners.is_empty() {
+        spenders.remove(&spender);
+      }
+    }
+  });
+  ALLOWS.with(|a| {
+    let mut allowances = a.borrow_mut();
+    if value != 0 {
+      allowances
+        .entry(owner)
+        .or_insert_with(HashMap::new)
+        .insert(spender, value);
+    } else if let Some(inner) = allowances.get_mut(&owner) {
+      inner.remove(&spender);
+      if inner.is_empty() {
+        allowances.remove(&owner);
+      }
+    }
+  });
+}
+
+fn _to_approve_error(owner: Principal, error: TxError) -> ApproveError {
+  match error {
+    TxError::InsufficientBalance => ApproveError::InsufficientFunds {
+      balance: balance_of(owner),
+    },
+    error => ApproveError::GenericError {
+      error_code: Nat::from(0),
+      message: format!("{:?}", error),
+    },
+  }
+}
+
+fn _expiry_get(owner: Principal, spender: Principal) -> Option<u64> {
+  EXPIRIES.with(|e| e.borrow().get(&(owner, spender)).copied())
+}
+
+fn _expiry_set(owner: Principal, spender: Principal, expires_at: Option<u64>) {
+  EXPIRIES.with(|e| {
+    let mut expiries = e.borrow_mut();
+    match expires_at {
+      Some(expires_at) => expiries.insert((owner, spender), expires_at),
+      None => expiries.remove(&(owner, spender)),
+    };
+  });
+}
+
+fn _is_expired(owner: Principal, spender: Principal) -> bool {
+  match _expiry_get(owner, spender) {
+    Some(expires_at) => expires_at <= ic::time(),
+    None => false,
+  }
+}
+
+fn _transfer(from: Principal, to: Principal, value: Nat) -> Result<(), TxError> {
+  if from == to {
+    return Ok(());
+  }
+  let from_balance = balance_of(from);
+  let from_balance_new = match _try_sub(from_balance, value.clone()) {
+    Some(balance) => balance,
+    None => return Err(TxError::InsufficientBalance),
+  };
+
+  // TODO: check this logic ↴
+  if from_balance_new != 0 {
+    _balance_ins(from, from_balance_new);
+  } else {
+    _balance_rem(from)
+  }
+  let to_balance = balance_of(to);
+  let to_balance_new = to_balance + value;
+  if to_balance_new != 0 {
+    _balance_ins(to, to_balance_new);
+  }
+  Ok(())
+}
+
+// Removes `amount` from `from`'s balance and from the total supply.
+fn _burn(from: Principal, amount: Nat) -> Result<(), TxError> {
+  let from_balance_new = match _try_sub(balance_of(from), amount.clone()) {
+    Some(balance) => balance,
+    None => return Err(TxError::InsufficientBalance),
+  };
+  let total_supply_new = match _try_sub(total_supply(), amount) {
+    Some(supply) => supply,
+    None => return Err(TxError::InsufficientBalance),
+  };
+  if from_balance_new != 0 {
+    _balance_ins(from, from_balance_new);
+  } else {
+    _balance_rem(from);
+  }
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    stats.total_supply = total_supply_new;
+  });
+  _supply_point();
+  Ok(())
+}
+
+// Appends the current total supply to the supply history, dropping the
+// oldest points beyond `max_supply_points`.
+fn _supply_point() {
+  let (total_supply, max_supply_points) = STATS.with(|s| {
+    let stats = s.borrow();
+    (
+      stats.total_supply.clone(),
+      stats.max_supply_points.unwrap_or(DEFAULT_MAX_SUPPLY_POINTS),
+    )
+  });
+  SUPPLY_HISTORY.with(|h| {
+    let mut supply_history = h.borrow_mut();
+    supply_history.push_back((ic::time(), total_supply));
+    while supply_history.len() > max_supply_points {
+      supply_history.pop_front();
+    }
+  });
+}
+
+fn _charge_fee(user: Principal, fee: Nat) -> Result<(), TxError> {
+  let (fee_to, burn_fees) = STATS.with(|s| {
+    let stats = s.borrow();
+    (stats.fee_to, stats.burn_fees)
+  });
+  if fee == 0 {
+    Ok(())
+  } else if burn_fees {
+    _burn(user, fee.clone())?;
+    _history_inc();
+    _add_record_detached(
+      user,
+      Operation::Burn,
+      user,
+      user,
+      fee,
+      Nat::from(0),
+      ic::time(),
+      TransactionStatus::Succeeded,
+    );
+    Ok(())
+  } else {
+    _transfer(user, fee_to, fee)
+  }
+}
+
+fn _get_max_representable_supply() -> Nat {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    stats
+      .max_representable_supply
+      .clone()
+      .unwrap_or_else(|| Nat::from(u128::MAX))
+  })
+}
+
+fn _get_min_approval() -> Nat {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    stats.min_approval.clone()
+  })
+}
+
+fn _require_cap_success() -> bool {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    stats.require_cap_success
+  })
+}
+
+fn _is_fee_exempt(payer: Principal) -> bool {
+  FEE_EXEMPT.with(|f| f.borrow().contains(&payer))
+}
+
+// Fee `payer` is charged for an approval, nothing for fee exempt principals.
+fn _get_fee(payer: Principal) -> Nat {
+  if _is_fee_exempt(payer) {
+    return Nat::from(0);
+  }
+  STATS.with(|s| {
+    let stats = s.borrow();
+    stats.fee.clone()
+  })
+}
+
+// Fee `payer` is charged in this token by transfers, nothing when the fee is
+// collected in a separate fee token or `payer` is fee exempt.
+fn _transfer_fee(payer: Principal) -> Nat {
+  if _is_fee_exempt(payer) {
+    return Nat::from(0);
+  }
+  STATS.with(|s| {
+    let stats = s.borrow();
+    match stats.fee_token {
+      Some(_) => Nat::from(0),
+      None => stats.fee.clone(),
+    }
+  })
+}
+
+// With a fee token configured, pulls the fee from `from` into `fee_to` with a
+// `transferFrom` on the fee token, so `from` has to have approved this
+// canister there. The transfer checks run first so a transfer that would
+// fail doesn't cost the fee, and the collected fee is returned so it can be
+// refunded when the transfer fails anyway, see `_refund_on_error`.
+async fn _collect_token_fee(
+  from: Principal,
+  to: Principal,
+  value: Nat,
+) -> Result<Option<TokenFee>, TxError> {
+  let (fee_token, fee_token_amount, fee_to) = STATS.with(|s| {
+    let stats = s.borrow();
+    (
+      stats.fee_token,
+      stats.fee_token_amount.clone(),
+      stats.fee_to,
+    )
+  });
+  let fee_token = match fee_token {
+    Some(fee_token) => fee_token,
+    None => return Ok(None),
+  };
+  // `_mutate` would trap after the fee token was already charged
+  if _is_recording(ic::caller()) {
+    return Err(TxError::Other(
+      "a previous call is still being recorded".to_string(),
+    ));
+  }
+  _check_transfer(from, to, value)?;
+  if fee_token_amount == 0 || _is_fee_exempt(from) {
+    return Ok(None);
+  }
+  let result: Result<(TxReceipt,), _> = ic::call(
+    fee_token,
+    "transferFrom",
+    (from, fee_to, fee_token_amount.clone()),
+  )
+  .await;
+  match result {
+    Ok((Ok(_),)) => Ok(Some(TokenFee {
+      fee_token,
+      payer: from,
+      fee_to,
+      amount: fee_token_amount,
+    })),
+    Ok((Err(error),)) => Err(TxError::Other(format!(
+      "collecting the fee token failed: {:?}",
+      error
+    ))),
+    Err((_, reason)) => Err(TxError::Other(format!(
+      "calling the fee token failed: {}",
+      reason
+    ))),
+  }
+}
+
+// Pays a fee collected by `_collect_token_fee` back when `result`, the
+// transfer it was collected for, failed after all.
+async fn _refund_on_error<T>(
+  token_fee: Option<TokenFee>,
+  result: Result<T, TxError>,
+) -> Result<T, TxError> {
+  if let (Err(_), Some(fee)) = (&result, token_fee) {
+    _refund_token_fee(fee).await;
+  }
+  result
+}
+
+// The refund is a `transferFrom` out of `fee_to` on the fee token, so
+// `fee_to` has to have approved this canister there; a failed refund is only
+// logged.
+async fn _refund_token_fee(fee: TokenFee) {
+  let refund: Result<(TxReceipt,), _> = ic::call(
+    fee.fee_token,
+    "transferFrom",
+    (fee.fee_to, fee.payer, fee.amount.clone()),
+  )
+  .await;
+  if !matches!(refund, Ok((Ok(_),))) {
+    ic::print(format!(
+      "refunding the fee of {} to {} failed: {:?}",
+      fee.amount, fee.payer, refund
+    ));
+  }
+}
+
+// The allowance `transferFrom` needs, checked before a fee token fee is
+// collected so a spender without one doesn't cost `owner` the fee.
+fn _check_allowance(
+  owner: Principal,
+  spender: Principal,
+  to: Principal,
+  value: &Nat,
+) -> Result<(), TxError> {
+  if allowance(owner, spender) < _allowance_spent(owner, to, value, &_transfer_fee(owner)) {
+    return Err(TxError::InsufficientAllowance);
+  }
+  Ok(())
+}
+
+fn _get_owner() -> Principal {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    stats.owner
+  })
+}
+
+fn _tx_counter_inc() -> usize {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    let index = stats.tx_counter;
+    stats.tx_counter += 1;
+    index
+  })
+}
+
+fn _operation_count_inc(op: &Operation) {
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    match op {
+      Operation::Mint => stats.mint_count += 1,
+      Operation::Burn => stats.burn_count += 1,
+      Operation::Transfer | Operation::TransferFrom => stats.transfer_count += 1,
+      Operation::Approve => stats.approve_count += 1,
+    }
+  });
+}
+
+fn _last_tx_index() -> usize {
+  STATS.with(|s| {
+    let stats = s.borrow();
+    stats.tx_counter - 1
+  })
+}
+
+fn _check_signed_nonce(owner: Principal, nonce: u64) -> Result<(), TxError> {
+  let expected = get_signed_nonce(owner);
+  if nonce == expected {
+    Ok(())
+  } else {
+    Err(TxError::Other(format!("expected nonce {}", expected)))
+  }
+}
+
+fn _nonce_get(user: Principal, nonce: u64) -> Option<Nat> {
+  NONCES.with(|n| {
+    let nonces = n.borrow();
+    nonces
+      .get(&user)?
+      .iter()
+      .find(|(used, _)| *used == nonce)
+      .map(|(_, index)| index.clone())
+  })
+}
+
+fn _nonce_set(user: Principal, nonce: u64, index: Nat) {
+  NONCES.with(|n| {
+    let mut nonces = n.borrow_mut();
+    let used = nonces.entry(user).or_default();
+    used.push_back((nonce, index));
+    if used.len() > MAX_NONCES_PER_PRINCIPAL {
+      used.pop_front();
+    }
+  });
+}
+
+fn _memo_set(index: usize, memo: Vec<u8>) {
+  HISTORY.with(|h| {
+    let mut history = h.borrow_mut();
+    history.memos.insert(index, memo);
+  });
+}
+
+fn _history_inc() {
   STATS.with(|s| {
     let mut stats = s.borrow_mut();
     stats.history_size += 1;
@@ -636,28 +4068,131 @@ fn main() {
   std::print!("{}", __export_service());
 }
 
+// Balances and allowances can outgrow a single write, so they are stored in
+// chunks next to the rest of the state, see `stable.rs`.
 #[pre_upgrade]
 fn pre_upgrade() {
+  _save_state(&mut StableWriter::default());
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+  // Version 2 only added the version tag to the chunked layout of version
+  // 1, both store the same values.
+  let (state, extras) = match stable::version() {
+    0 => {
+      let (stats, balances, allowances, tx_log, cap) =
+        ic::stable_restore::<StableStateV0>().unwrap();
+      let state = (
+        _migrate_v0(stats),
+        balances,
+        allowances,
+        tx_log,
+        cap,
+        TxHistory::default(),
+        HashSet::new(),
+        HashMap::new(),
+        Expiries::new(),
+        Nonces::new(),
+        HashSet::new(),
+        HashMap::new(),
+      );
+      (state, StableExtras::default())
+    }
+    1 | DATA_VERSION => _restore_chunked(&mut StableReader::default()),
+    version => ic::trap(&format!("unknown stable layout version {}", version)),
+  };
+  _load_state(state, extras);
+}
+
+// Writes the state in the chunked layout of `DATA_VERSION`.
+fn _save_state<W: Write>(writer: &mut W) {
   let stats = STATS.with(|s| s.borrow().clone());
-  let balances = BALANCES.with(|b| b.borrow().clone());
-  let allows = ALLOWS.with(|a| a.borrow().clone());
+  let balances: Vec<(Principal, Nat)> = BALANCES.with(|b| {
+    let balances = b.borrow();
+    balances
+      .iter()
+      .map(|(who, balance)| (*who, balance.clone()))
+      .collect()
+  });
+  let allows: Vec<(Principal, Principal, Nat)> = ALLOWS.with(|a| {
+    let allowances = a.borrow();
+    allowances
+      .iter()
+      .flat_map(|(owner, inner)| {
+        inner
+          .iter()
+          .map(move |(spender, value)| (*owner, *spender, value.clone()))
+      })
+      .collect()
+  });
   let tx_log = TXLOG.with(|t| t.borrow().clone());
+  let history = HISTORY.with(|h| h.borrow().clone());
+  let blocklist = BLOCKLIST.with(|b| b.borrow().clone());
+  let minters = MINTERS.with(|m| m.borrow().clone());
+  let expiries = EXPIRIES.with(|e| e.borrow().clone());
+  let nonces = NONCES.with(|n| n.borrow().clone());
+  let excluded = EXCLUDED.with(|e| e.borrow().clone());
+  let listeners = LISTENERS.with(|l| l.borrow().clone());
+  let extras = StableExtras {
+    spenders: Some(SPENDERS.with(|s| s.borrow().clone())),
+    signed_nonces: SIGNED_NONCES.with(|n| n.borrow().clone()),
+    last_transfer: LAST_TRANSFER.with(|l| l.borrow().clone()),
+    snapshots: SnapshotCopies::default(),
+    supply_history: Some(SUPPLY_HISTORY.with(|h| h.borrow().clone())),
+    burn_sinks: Some(BURN_SINKS.with(|b| b.borrow().clone())),
+    drawdowns: Some(DRAWDOWNS.with(|d| d.borrow().clone())),
+    fee_exempt: Some(FEE_EXEMPT.with(|f| f.borrow().clone())),
+    cap_queue: Some(CAP_QUEUE.with(|q| q.borrow().clone())),
+    snapshot_diffs: Some(SNAPSHOTS.with(|s| s.borrow().clone())),
+  };
   let cap = archive();
-  ic::stable_store((stats, balances, allows, tx_log, cap)).unwrap();
+  stable::save_to(
+    writer,
+    DATA_VERSION,
+    (
+      stats, tx_log, cap, history, blocklist, minters, expiries, nonces, excluded, listeners,
+      extras,
+    ),
+    &balances,
+    &allows,
+  );
 }
 
-#[post_upgrade]
-fn post_upgrade() {
-  let (metadata_stored, balances_stored, allowances_stored, tx_log_stored, cap_store): (
-    StatsData,
-    Balances,
-    Allowances,
-    TxLog,
-    Archive,
-  ) = ic::stable_restore().unwrap();
+// Puts restored state in place, repairing it first when `repair_on_upgrade`
+// is set.
+fn _load_state(state: StableState, extras: StableExtras) {
+  let (
+    metadata_stored,
+    balances_stored,
+    allowances_stored,
+    tx_log_stored,
+    cap_store,
+    history_stored,
+    blocklist_stored,
+    minters_stored,
+    expiries_stored,
+    nonces_stored,
+    excluded_stored,
+    listeners_stored,
+  ) = state;
+  let StableExtras {
+    spenders: spenders_stored,
+    signed_nonces: signed_nonces_stored,
+    last_transfer: last_transfer_stored,
+    snapshots: snapshots_stored,
+    supply_history: supply_history_stored,
+    burn_sinks: burn_sinks_stored,
+    drawdowns: drawdowns_stored,
+    fee_exempt: fee_exempt_stored,
+    cap_queue: cap_queue_stored,
+    snapshot_diffs: snapshot_diffs_stored,
+  } = extras;
   STATS.with(|s| {
     let mut stats = s.borrow_mut();
     *stats = metadata_stored;
+    stats.upgrade_count += 1;
+    stats.last_upgrade_time = ic::time();
   });
   BALANCES.with(|b| {
     let mut balances = b.borrow_mut();
@@ -667,14 +4202,293 @@ fn post_upgrade() {
     let mut allowances = a.borrow_mut();
     *allowances = allowances_stored;
   });
+  SPENDERS.with(|s| {
+    let mut spenders = s.borrow_mut();
+    *spenders = spenders_stored.unwrap_or_else(_index_spenders);
+  });
   TXLOG.with(|t| {
     let mut tx_log = t.borrow_mut();
     *tx_log = tx_log_stored;
   });
+  HISTORY.with(|h| {
+    let mut history = h.borrow_mut();
+    *history = history_stored;
+    if history.op_index.is_none() {
+      history.index_operations();
+    }
+  });
+  _certify_tip();
+  BLOCKLIST.with(|b| {
+    let mut blocklist = b.borrow_mut();
+    *blocklist = blocklist_stored;
+  });
+  MINTERS.with(|m| {
+    let mut minters = m.borrow_mut();
+    *minters = minters_stored;
+  });
+  EXPIRIES.with(|e| {
+    let mut expiries = e.borrow_mut();
+    *expiries = expiries_stored;
+  });
+  NONCES.with(|n| {
+    let mut nonces = n.borrow_mut();
+    *nonces = nonces_stored;
+  });
+  EXCLUDED.with(|e| {
+    let mut excluded = e.borrow_mut();
+    *excluded = excluded_stored;
+  });
+  LISTENERS.with(|l| {
+    let mut listeners = l.borrow_mut();
+    *listeners = listeners_stored;
+  });
+  SIGNED_NONCES.with(|n| {
+    let mut signed_nonces = n.borrow_mut();
+    *signed_nonces = signed_nonces_stored;
+  });
+  LAST_TRANSFER.with(|l| {
+    let mut last_transfer = l.borrow_mut();
+    *last_transfer = last_transfer_stored;
+  });
+  SNAPSHOTS.with(|s| {
+    let mut snapshots = s.borrow_mut();
+    *snapshots = snapshot_diffs_stored
+      .unwrap_or_else(|| BALANCES.with(|b| _migrate_snapshots(snapshots_stored, &b.borrow())));
+  });
+  SUPPLY_HISTORY.with(|h| {
+    let mut supply_history = h.borrow_mut();
+    *supply_history = supply_history_stored.unwrap_or_default();
+  });
+  BURN_SINKS.with(|b| {
+    let mut burn_sinks = b.borrow_mut();
+    *burn_sinks = burn_sinks_stored.unwrap_or_default();
+  });
+  DRAWDOWNS.with(|d| {
+    let mut drawdowns = d.borrow_mut();
+    *drawdowns = drawdowns_stored.unwrap_or_default();
+  });
+  FEE_EXEMPT.with(|f| {
+    let mut fee_exempt = f.borrow_mut();
+    *fee_exempt = fee_exempt_stored.unwrap_or_default();
+  });
+  CAP_QUEUE.with(|q| {
+    let mut cap_queue = q.borrow_mut();
+    *cap_queue = cap_queue_stored.unwrap_or_default();
+  });
+  if STATS.with(|s| s.borrow().repair_on_upgrade) {
+    _repair_state();
+  }
   from_archive(cap_store);
 }
 
-async fn add_record(
+// Everything the unversioned layout didn't store starts from the defaults.
+// Indexes continue after the records it counted.
+fn _migrate_v0(stats: StatsDataV0) -> StatsData {
+  StatsData {
+    logo: stats.logo,
+    name: stats.name,
+    symbol: stats.symbol,
+    decimals: stats.decimals,
+    total_supply: stats.total_supply,
+    owner: stats.owner,
+    fee: stats.fee,
+    fee_to: stats.fee_to,
+    history_size: stats.history_size,
+    deploy_time: stats.deploy_time,
+    tx_counter: stats.history_size,
+    ..StatsData::default()
+  }
+}
+
+fn _restore_chunked<R: Read>(reader: &mut R) -> (StableState, StableExtras) {
+  let (
+    (
+      stats,
+      tx_log,
+      cap,
+      history,
+      blocklist,
+      minters,
+      expiries,
+      nonces,
+      excluded,
+      listeners,
+      extras,
+    ),
+    balances,
+    allows,
+  ): (StableValues, Vec<_>, Vec<_>) = stable::restore_from(reader);
+  let balances = Balances::from_iter(balances);
+  let mut allowances = Allowances::new();
+  for (owner, spender, value) in allows {
+    allowances.entry(owner).or_default().insert(spender, value);
+  }
+  (
+    (
+      stats, balances, allowances, tx_log, cap, history, blocklist, minters, expiries, nonces,
+      excluded, listeners,
+    ),
+    extras,
+  )
+}
+
+// Reverse index of `ALLOWS`, from each spender to the owners that approved it.
+fn _index_spenders() -> Spenders {
+  ALLOWS.with(|a| {
+    let allowances = a.borrow();
+    let mut spenders = Spenders::new();
+    for (owner, inner) in allowances.iter() {
+      for spender in inner.keys() {
+        spenders.entry(*spender).or_default().insert(*owner);
+      }
+    }
+    spenders
+  })
+}
+
+// Drops zero balances, returning how many, and transfer times past the
+// cooldown or the rate limit window.
+fn _compact_balances() -> usize {
+  let cooldown = _cooldown_nanos();
+  let now = ic::time();
+  LAST_TRANSFER.with(|l| {
+    let mut last_transfer = l.borrow_mut();
+    last_transfer.retain(|_, last| now.saturating_sub(*last) < cooldown);
+  });
+  let window = STATS.with(|s| s.borrow().rate_limit.map_or(0, |(_, window)| window));
+  TRANSFER_TIMES.with(|t| {
+    let mut times = t.borrow_mut();
+    times.retain(|_, at| {
+      _drop_times_before(at, now, window);
+      !at.is_empty()
+    });
+  });
+  BALANCES.with(|b| {
+    let mut balances = b.borrow_mut();
+    let before = balances.len();
+    balances.retain(|_, balance| *balance != 0);
+    before - balances.len()
+  })
+}
+
+// Drops zero balances and empty allowances and recomputes the total supply
+// from the balances, logging every change.
+fn _repair_state() {
+  let zero_balances = _compact_balances();
+  ic::print(format!("repair: removed {} zero balances", zero_balances));
+  let empty_allowances = ALLOWS.with(|a| {
+    let mut allowances = a.borrow_mut();
+    let mut removed = 0;
+    allowances.retain(|_, inner| {
+      let before = inner.len();
+      inner.retain(|_, value| *value != 0);
+      removed += before - inner.len();
+      !inner.is_empty()
+    });
+    removed
+  });
+  ic::print(format!(
+    "repair: removed {} empty allowances",
+    empty_allowances
+  ));
+  SPENDERS.with(|s| {
+    let mut spenders = s.borrow_mut();
+    *spenders = _index_spenders();
+  });
+  let total_supply = BALANCES.with(|b| {
+    let balances = b.borrow();
+    balances
+      .values()
+      .fold(Nat::from(0), |sum, balance| sum + balance.clone())
+  });
+  STATS.with(|s| {
+    let mut stats = s.borrow_mut();
+    if stats.total_supply != total_supply {
+      ic::print(format!(
+        "repair: total supply {} recomputed as {}",
+        stats.total_supply, total_supply
+      ));
+      stats.total_supply = total_supply;
+    }
+    stats.repair_on_upgrade = false;
+  });
+}
+
+// The record is stored locally and gets its index as soon as this is called,
+// only the CAP insert happens in the returned future. The receipt carries the
+// local index, the id assigned by CAP is only logged.
+#[allow(clippy::too_many_arguments)]
+fn add_record(
+  caller: Principal,
+  op: Operation,
+  from: Principal,
+  to: Principal,
+  amount: Nat,
+  fee: Nat,
+  timestamp: u64,
+  status: TransactionStatus,
+) -> impl Future<Output = TxReceipt> {
+  _notify_listeners(from, to, amount.clone(), op.clone());
+  let (index, ie) = _record(caller, op, from, to, amount, fee, timestamp, status);
+  let insert = _cap_insert(ie);
+  let recording = insert.as_ref().map(|_| Recording::start(ic::caller()));
+  async move {
+    let _recording = recording;
+    if let Some(insert) = insert {
+      match insert.await {
+        Ok(cap_id) => ic::print(format!("tx {} inserted into cap with id {}", index, cap_id)),
+        Err(error) => ic::print(format!("tx {} queued for cap: {:?}", index, error)),
+      }
+    }
+    Ok(Nat::from(index))
+  }
+}
+
+// Inserts `ie` into CAP right away, or queues it for the heartbeat when
+// records are batched.
+fn _cap_insert(ie: IndefiniteEvent) -> Option<impl Future<Output = TxReceipt>> {
+  let batch_size = STATS.with(|s| s.borrow().cap_batch_size.unwrap_or(0));
+  if batch_size == 0 {
+    Some(insert_into_cap(ie))
+  } else {
+    CAP_QUEUE.with(|q| q.borrow_mut().push_back(ie));
+    None
+  }
+}
+
+// Like `add_record`, but the CAP insert happens right away even when records
+// are batched. If it fails the future returns the record's index and event
+// without putting the event on the backlog, the caller decides whether it
+// still goes to CAP.
+#[allow(clippy::too_many_arguments)]
+fn add_record_strict(
+  caller: Principal,
+  op: Operation,
+  from: Principal,
+  to: Principal,
+  amount: Nat,
+  fee: Nat,
+  timestamp: u64,
+  status: TransactionStatus,
+) -> impl Future<Output = Result<usize, (usize, IndefiniteEvent)>> {
+  _notify_listeners(from, to, amount.clone(), op.clone());
+  let (index, ie) = _record(caller, op, from, to, amount, fee, timestamp, status);
+  let recording = Recording::start(ic::caller());
+  async move {
+    let _recording = recording;
+    _retry_failed_insert().await;
+    match insert(ie.clone()).await {
+      Ok(_) => Ok(index),
+      Err(_) => Err((index, ie)),
+    }
+  }
+}
+
+// Like `add_record` for callers that can't await the CAP insert. The event
+// is queued when records are batched, otherwise it joins the backlog that
+// the next insert or `flushCapBacklog` sends.
+#[allow(clippy::too_many_arguments)]
+fn _add_record_detached(
   caller: Principal,
   op: Operation,
   from: Principal,
@@ -683,37 +4497,113 @@ async fn add_record(
   fee: Nat,
   timestamp: u64,
   status: TransactionStatus,
-) -> TxReceipt {
-  insert_into_cap(Into::<IndefiniteEvent>::into(Into::<Event>::into(Into::<
-    TypedEvent<DIP20Details>,
-  >::into(
-    TxRecord {
-      caller: Some(caller),
-      index: Nat::from(0),
-      from,
-      to,
-      amount: Nat::from(amount),
-      fee: Nat::from(fee),
-      timestamp: Int::from(timestamp),
-      status,
-      operation: op,
-    },
-  ))))
-  .await
+) -> usize {
+  _notify_listeners(from, to, amount.clone(), op.clone());
+  let (index, ie) = _record(caller, op, from, to, amount, fee, timestamp, status);
+  let batch_size = STATS.with(|s| s.borrow().cap_batch_size.unwrap_or(0));
+  if batch_size == 0 {
+    TXLOG.with(|t| t.borrow_mut().ie_records.push_back(ie));
+  } else {
+    CAP_QUEUE.with(|q| q.borrow_mut().push_back(ie));
+  }
+  index
+}
+
+// Sends `(from, to, amount, op)` to every registered listener for all
+// operations but approvals. The calls are spawned so the caller doesn't wait
+// for the replies, a failure is only logged.
+fn _notify_listeners(from: Principal, to: Principal, amount: Nat, op: Operation) {
+  if let Operation::Approve = op {
+    return;
+  }
+  let listeners = LISTENERS.with(|l| l.borrow().clone());
+  for (canister, method) in listeners {
+    let args = (from, to, amount.clone(), op.clone());
+    ic::spawn(async move {
+      let notified: Result<(), _> = ic::call(canister, method, args).await;
+      if let Err((code, reason)) = notified {
+        ic::print(format!(
+          "notifying {} failed: {:?} {}",
+          canister, code, reason
+        ));
+      }
+    });
+  }
+}
+
+// Stores the record locally under the next index, returning that index and
+// the event to insert into CAP.
+#[allow(clippy::too_many_arguments)]
+fn _record(
+  caller: Principal,
+  op: Operation,
+  from: Principal,
+  to: Principal,
+  amount: Nat,
+  fee: Nat,
+  timestamp: u64,
+  status: TransactionStatus,
+) -> (usize, IndefiniteEvent) {
+  let index = _tx_counter_inc();
+  _operation_count_inc(&op);
+  let record = TxRecord {
+    caller: Some(caller),
+    index: Nat::from(index),
+    from,
+    to,
+    amount,
+    fee,
+    timestamp: Int::from(timestamp),
+    status,
+    operation: op,
+  };
+  _history_push(record.clone());
+  (index, _to_event(record))
+}
+
+fn _to_event(record: TxRecord) -> IndefiniteEvent {
+  Into::<IndefiniteEvent>::into(Into::<Event>::into(Into::<TypedEvent<DIP20Details>>::into(
+    record,
+  )))
+}
+
+fn _history_push(record: TxRecord) {
+  HISTORY.with(|h| {
+    let mut history = h.borrow_mut();
+    history.push(record);
+  });
+  _certify_tip();
+}
+
+fn _certify_tip() {
+  HISTORY.with(|h| {
+    let history = h.borrow();
+    if let Some(tip_hash) = &history.tip_hash {
+      icrc3::certify(history.evicted + history.records.len() - 1, tip_hash);
+    }
+  });
 }
 
 pub async fn insert_into_cap(ie: IndefiniteEvent) -> TxReceipt {
-  let mut tx_log = TXLOG.with(|t| t.take());
-  if let Some(failed_ie) = tx_log.ie_records.pop_front() {
+  _retry_failed_insert().await;
+  insert_into_cap_priv(ie).await
+}
+
+// Sends the oldest event of the backlog again, a failure puts it back.
+async fn _retry_failed_insert() {
+  let failed = TXLOG.with(|t| {
+    let mut tx_log = t.borrow_mut();
+    tx_log.ie_records.pop_front()
+  });
+  if let Some(failed_ie) = failed {
     let _ = insert_into_cap_priv(failed_ie).await;
   }
-  insert_into_cap_priv(ie).await
 }
 
 async fn insert_into_cap_priv(ie: IndefiniteEvent) -> TxReceipt {
   let insert_res = insert(ie.clone())
     .await
-    .map(|tx_id| Nat::from(tx_id))
+    .map(Nat::from)
     .map_err(|error| TxError::Other(format!("Inserting into cap failed with error: {:?}", error)));
 
   if insert_res.is_err() {
@@ -725,3 +4615,1358 @@ async fn insert_into_cap_priv(ie: IndefiniteEvent) -> TxReceipt {
 
   insert_res
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use async_std::task::block_on;
+  use ic_kit::mock_principals::{alice, bob, john};
+  use ic_kit::{Method, MockContext};
+
+  // A token owned by alice, who holds the whole supply of 1_000. Fees of 1
+  // go to john. Records are queued for CAP, so no test reaches it.
+  fn setup() {
+    MockContext::new().with_caller(alice()).inject();
+    STATS.with(|s| {
+      let mut stats = s.borrow_mut();
+      stats.name = "Token".to_string();
+      stats.symbol = "TKN".to_string();
+      stats.decimals = 8;
+      stats.total_supply = Nat::from(1_000);
+      stats.owner = alice();
+      stats.fee = Nat::from(1);
+      stats.fee_to = john();
+      stats.cap_batch_size = Some(usize::MAX);
+    });
+    _balance_ins(alice(), Nat::from(1_000));
+  }
+
+  fn field(block: &icrc3::Value, name: &str) -> Option<icrc3::Value> {
+    match block {
+      icrc3::Value::Map(fields) => fields
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.clone()),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn transfer_from_checks_allowance_before_collecting_fee_token() {
+    setup();
+    STATS.with(|s| {
+      let mut stats = s.borrow_mut();
+      stats.fee_token = Some(Principal::from_slice(&[9; 10]));
+      stats.fee_token_amount = Nat::from(5);
+    });
+    // Without a handler a call to the fee token fails, the allowance has to
+    // be checked before getting there.
+    MockContext::new().with_caller(bob()).inject();
+    assert_eq!(
+      block_on(transfer_from(alice(), john(), Nat::from(10))),
+      Err(TxError::InsufficientAllowance)
+    );
+    assert_eq!(
+      block_on(transfer_from_returning_allowance(
+        alice(),
+        john(),
+        Nat::from(10)
+      )),
+      Err(TxError::InsufficientAllowance)
+    );
+  }
+
+  #[test]
+  fn transfer_from_refunds_fee_token_on_failure() {
+    setup();
+    STATS.with(|s| {
+      let mut stats = s.borrow_mut();
+      stats.fee_token = Some(Principal::from_slice(&[9; 10]));
+      stats.fee_token_amount = Nat::from(5);
+    });
+    block_on(approve(bob(), Nat::from(100))).unwrap();
+    MockContext::new()
+      .with_caller(bob())
+      .with_handler(
+        Method::new()
+          .name("transferFrom")
+          .response(Ok::<Nat, TxError>(Nat::from(1))),
+      )
+      .inject();
+    BLOCKLIST.with(|b| b.borrow_mut().insert(bob()));
+    assert_eq!(
+      block_on(transfer_from(alice(), john(), Nat::from(10))),
+      Err(TxError::AccountBlocked)
+    );
+    assert_eq!(balance_of(john()), Nat::from(1));
+    assert_eq!(allowance(alice(), bob()), Nat::from(100));
+
+    BLOCKLIST.with(|b| b.borrow_mut().remove(&bob()));
+    assert!(block_on(transfer_from(alice(), john(), Nat::from(10))).is_ok());
+    assert_eq!(balance_of(john()), Nat::from(11));
+    assert_eq!(allowance(alice(), bob()), Nat::from(90));
+  }
+
+  #[test]
+  fn strict_transfer_is_rolled_back_with_compensating_records() {
+    setup();
+    STATS.with(|s| {
+      let mut stats = s.borrow_mut();
+      stats.require_cap_success = true;
+      stats.transfer_cooldown_secs = 60;
+    });
+    // Without a handler the CAP insert fails.
+    assert_eq!(
+      block_on(transfer(bob(), Nat::from(10))),
+      Err(TxError::Other("history unavailable".to_string()))
+    );
+    assert_eq!(balance_of(alice()), Nat::from(1_000));
+    assert_eq!(balance_of(bob()), Nat::from(0));
+    assert_eq!(balance_of(john()), Nat::from(0));
+    assert!(LAST_TRANSFER.with(|l| l.borrow().is_empty()));
+
+    let records = HISTORY.with(|h| h.borrow().records.clone());
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].from, alice());
+    assert_eq!(records[0].status, TransactionStatus::Succeeded);
+    assert_eq!((records[1].from, records[1].to), (bob(), alice()));
+    assert_eq!(records[1].amount, Nat::from(10));
+    assert_eq!((records[2].from, records[2].to), (john(), alice()));
+    assert_eq!(records[2].amount, Nat::from(1));
+  }
+
+  #[test]
+  fn rolled_back_transfers_never_reach_cap() {
+    setup();
+    STATS.with(|s| s.borrow_mut().require_cap_success = true);
+    assert!(block_on(transfer(bob(), Nat::from(10))).is_err());
+    assert_eq!(get_cap_backlog_size(), 0);
+    assert!(CAP_QUEUE.with(|q| q.borrow().is_empty()));
+
+    MockContext::new()
+      .with_caller(alice())
+      .with_handler(Method::new().name("insert").response(Nat::from(1)))
+      .inject();
+    assert_eq!(block_on(flush_cap_backlog()), Ok(0));
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    assert_eq!(balance_of(bob()), Nat::from(10));
+    assert_eq!(get_cap_backlog_size(), 0);
+  }
+
+  #[test]
+  fn transfers_without_strict_cap_succeed_when_cap_fails() {
+    setup();
+    STATS.with(|s| s.borrow_mut().cap_batch_size = Some(0));
+    // Without a handler the CAP insert fails and the record is kept for later.
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    assert_eq!(balance_of(bob()), Nat::from(10));
+    assert_eq!(get_cap_backlog_size(), 1);
+  }
+
+  #[test]
+  fn approve_and_notify_restores_allowance_and_refunds_fee() {
+    setup();
+    let spender = Principal::from_slice(&[9, 9, 9, 1]);
+    block_on(approve(spender, Nat::from(30))).unwrap();
+    assert_eq!(balance_of(john()), Nat::from(1));
+
+    // Without a handler notifying the spender fails.
+    assert!(block_on(approve_and_notify(
+      spender,
+      Nat::from(100),
+      "onApprove".to_string()
+    ))
+    .is_err());
+    assert_eq!(allowance(alice(), spender), Nat::from(30));
+    assert_eq!(balance_of(alice()), Nat::from(999));
+    assert_eq!(balance_of(john()), Nat::from(1));
+  }
+
+  #[test]
+  fn mint_accrued_keeps_the_schedule_when_minting_fails() {
+    setup();
+    STATS.with(|s| {
+      let mut stats = s.borrow_mut();
+      stats.emission_rate = Nat::from(2);
+      stats.last_mint_time = ic::time() - 10_000_000_000;
+    });
+    STATS.with(|s| s.borrow_mut().reject_anonymous = Some(true));
+    assert!(block_on(mint_accrued(Principal::anonymous())).is_err());
+    assert_eq!(
+      STATS.with(|s| s.borrow().last_mint_time),
+      ic::time() - 10_000_000_000
+    );
+
+    block_on(mint_accrued(bob())).unwrap();
+    assert_eq!(balance_of(bob()), Nat::from(20));
+    assert_eq!(STATS.with(|s| s.borrow().last_mint_time), ic::time());
+  }
+
+  #[test]
+  fn mint_batch_checks_the_cap_for_the_whole_batch() {
+    setup();
+    STATS.with(|s| s.borrow_mut().max_representable_supply = Some(Nat::from(1_100)));
+    let receipts = block_on(mint_batch(vec![
+      (bob(), Nat::from(60)),
+      (john(), Nat::from(60)),
+    ]));
+    assert_eq!(receipts, vec![Err(TxError::SupplyOverflow)]);
+    assert_eq!(balance_of(bob()), Nat::from(0));
+    assert_eq!(total_supply(), Nat::from(1_000));
+
+    let receipts = block_on(mint_batch(vec![
+      (bob(), Nat::from(60)),
+      (john(), Nat::from(40)),
+    ]));
+    assert!(receipts.iter().all(|receipt| receipt.is_ok()));
+    assert_eq!(total_supply(), Nat::from(1_100));
+  }
+
+  #[test]
+  fn rescale_decimals_scales_every_stored_amount() {
+    setup();
+    block_on(approve(bob(), Nat::from(30))).unwrap();
+    block_on(admin_mint(bob(), Nat::from(5))).unwrap();
+    let snapshot_id = snapshot();
+    block_on(rescale_decimals(10)).unwrap();
+
+    assert_eq!(decimals(), 10);
+    assert_eq!(total_supply(), Nat::from(100_500));
+    assert_eq!(balance_of(alice()), Nat::from(99_900));
+    assert_eq!(balance_of(bob()), Nat::from(500));
+    assert_eq!(allowance(alice(), bob()), Nat::from(3_000));
+    assert_eq!(_get_fee(alice()), Nat::from(100));
+    assert_eq!(
+      MINTERS.with(|m| m.borrow()[&alice()].clone()),
+      Nat::from(500)
+    );
+    assert_eq!(
+      DRAWDOWNS.with(|d| d.borrow()[&(alice(), bob())].clone()),
+      (Nat::from(3_000), Nat::from(0))
+    );
+    assert_eq!(balance_of_at(bob(), snapshot_id), Some(Nat::from(500)));
+    assert_eq!(total_supply_at(snapshot_id), Some(Nat::from(100_500)));
+    assert_eq!(
+      SUPPLY_HISTORY.with(|h| h.borrow().back().unwrap().1.clone()),
+      Nat::from(100_500)
+    );
+    let event = CAP_QUEUE.with(|q| q.borrow().back().cloned()).unwrap();
+    assert_eq!(event.operation, "rescaleDecimals");
+  }
+
+  #[test]
+  fn rescale_decimals_rejects_without_changing_anything() {
+    setup();
+    assert_eq!(
+      block_on(rescale_decimals(19)),
+      Err(TxError::Other("decimals must be at most 18".to_string()))
+    );
+    assert_eq!(
+      block_on(rescale_decimals(8)),
+      Err(TxError::Other("decimals can only be increased".to_string()))
+    );
+    STATS.with(|s| s.borrow_mut().total_supply = _get_max_representable_supply());
+    assert_eq!(block_on(rescale_decimals(9)), Err(TxError::SupplyOverflow));
+    MockContext::new().with_caller(bob()).inject();
+    assert_eq!(block_on(rescale_decimals(9)), Err(TxError::Unauthorized));
+    assert_eq!(decimals(), 8);
+    assert_eq!(balance_of(alice()), Nat::from(1_000));
+  }
+
+  #[test]
+  fn transfers_to_burn_sinks_burn() {
+    setup();
+    let sink = Principal::from_slice(&[8; 10]);
+    set_burn_sinks(vec![sink]);
+    let preview = preview_transfer(alice(), sink, Nat::from(10)).unwrap();
+    assert_eq!(preview.recipient_balance_after, Nat::from(0));
+
+    block_on(approve(bob(), Nat::from(100))).unwrap();
+    MockContext::new().with_caller(bob()).inject();
+    block_on(transfer_from(alice(), sink, Nat::from(10))).unwrap();
+    assert_eq!(balance_of(sink), Nat::from(0));
+    assert_eq!(total_supply(), Nat::from(990));
+    let records = HISTORY.with(|h| h.borrow().records.clone());
+    assert!(matches!(records.back().unwrap().operation, Operation::Burn));
+  }
+
+  #[test]
+  fn burned_fees_are_recorded() {
+    setup();
+    set_fee_destination(FeeDestination::Burn);
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    assert_eq!(total_supply(), Nat::from(999));
+    let records = HISTORY.with(|h| h.borrow().records.clone());
+    assert_eq!(records.len(), 2);
+    assert!(matches!(records[0].operation, Operation::Burn));
+    assert_eq!(records[0].amount, Nat::from(1));
+    assert!(matches!(records[1].operation, Operation::Transfer));
+    assert_eq!(STATS.with(|s| s.borrow().history_size), 2);
+  }
+
+  #[test]
+  fn init_puts_the_genesis_mint_on_the_backlog() {
+    MockContext::new().with_caller(alice()).inject();
+    init(
+      "logo".to_string(),
+      "Token".to_string(),
+      "TKN".to_string(),
+      8,
+      Nat::from(1_000),
+      alice(),
+      Nat::from(1),
+      john(),
+      Principal::from_slice(&[7; 10]),
+      None,
+      None,
+    );
+    assert_eq!(balance_of(alice()), Nat::from(1_000));
+    let records = HISTORY.with(|h| h.borrow().records.clone());
+    assert_eq!(records.len(), 1);
+    assert!(matches!(records[0].operation, Operation::Mint));
+    assert_eq!(TXLOG.with(|t| t.borrow().ie_records.len()), 1);
+  }
+
+  #[test]
+  fn holders_count_follows_transfers() {
+    setup();
+    assert_eq!(get_holders_count(), 1);
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    assert_eq!(get_holders_count(), 3);
+    MockContext::new().with_caller(bob()).inject();
+    block_on(transfer(alice(), Nat::from(9))).unwrap();
+    assert_eq!(get_holders_count(), 2);
+  }
+
+  #[test]
+  fn icrc2_approvals_map_subaccounts_to_derived_principals() {
+    setup();
+    let subaccount = vec![5; 32];
+    let spender = icrc2::Account {
+      owner: bob(),
+      subaccount: Some(subaccount.clone()),
+    };
+    let approved = block_on(icrc2_approve(ApproveArgs {
+      from_subaccount: None,
+      spender: spender.clone(),
+      amount: Nat::from(40),
+      expected_allowance: None,
+      expires_at: None,
+      fee: None,
+      memo: None,
+      created_at_time: None,
+    }));
+    assert!(approved.is_ok());
+    let derived = derive_subaccount_principal(bob(), subaccount);
+    assert_eq!(allowance(alice(), derived), Nat::from(40));
+    assert_eq!(allowance(alice(), bob()), Nat::from(0));
+    let allowed = icrc2_allowance(AllowanceArgs {
+      account: icrc2::Account {
+        owner: alice(),
+        subaccount: Some(vec![0; 32]),
+      },
+      spender,
+    });
+    assert_eq!(allowed.allowance, Nat::from(40));
+  }
+
+  #[test]
+  fn supply_events_merge_mints_and_burns() {
+    setup();
+    block_on(admin_mint(bob(), Nat::from(5))).unwrap();
+    set_fee_destination(FeeDestination::Burn);
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    block_on(admin_mint(john(), Nat::from(7))).unwrap();
+
+    let events = supply_events(0, 10);
+    let amounts: Vec<Nat> = events.iter().map(|record| record.amount.clone()).collect();
+    assert_eq!(amounts, vec![Nat::from(5), Nat::from(1), Nat::from(7)]);
+    assert!(matches!(events[1].operation, Operation::Burn));
+    assert_eq!(supply_events(1, 1)[0].amount, Nat::from(1));
+  }
+
+  #[test]
+  fn transfer_with_nonce_returns_the_first_receipt() {
+    setup();
+    let first = block_on(transfer_with_nonce(bob(), Nat::from(10), 7)).unwrap();
+    let again = block_on(transfer_with_nonce(bob(), Nat::from(10), 7)).unwrap();
+    assert_eq!(first, again);
+    assert_eq!(balance_of(bob()), Nat::from(10));
+  }
+
+  #[test]
+  fn fee_to_counts_as_a_new_holder() {
+    setup();
+    set_max_holders(Some(2));
+    // bob would be the second holder, but the fee makes john the third
+    assert_eq!(
+      block_on(transfer(bob(), Nat::from(10))),
+      Err(TxError::MaxHoldersReached)
+    );
+    set_max_holders(Some(3));
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    assert_eq!(get_holders_count(), 3);
+  }
+
+  #[test]
+  fn rate_limit_times_are_pruned_per_user() {
+    setup();
+    block_on(transfer(bob(), Nat::from(100))).unwrap();
+    STATS.with(|s| s.borrow_mut().rate_limit = Some((2, 1_000)));
+    let handle = MockContext::new().with_caller(bob()).inject();
+    block_on(transfer(john(), Nat::from(1))).unwrap();
+    block_on(transfer(john(), Nat::from(1))).unwrap();
+    assert_eq!(
+      block_on(transfer(john(), Nat::from(1))),
+      Err(TxError::Other("rate limited".to_string()))
+    );
+
+    TRANSFER_TIMES.with(|t| t.borrow_mut().insert(bob(), VecDeque::from(vec![0, 1])));
+    handle.update_caller(john());
+    block_on(transfer(alice(), Nat::from(1))).unwrap();
+    // bob's times are stale, but only his own next transfer drops them
+    assert_eq!(TRANSFER_TIMES.with(|t| t.borrow()[&bob()].len()), 2);
+    compact_balances();
+    assert!(TRANSFER_TIMES.with(|t| !t.borrow().contains_key(&bob())));
+    assert_eq!(TRANSFER_TIMES.with(|t| t.borrow()[&john()].len()), 1);
+  }
+
+  #[test]
+  fn snapshots_keep_balances_lazily() {
+    setup();
+    let first = snapshot();
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    let second = snapshot();
+    let third = snapshot();
+    block_on(transfer(bob(), Nat::from(20))).unwrap();
+
+    assert_eq!(balance_of_at(alice(), first), Some(Nat::from(1_000)));
+    assert_eq!(balance_of_at(bob(), first), Some(Nat::from(0)));
+    assert_eq!(balance_of_at(alice(), second), Some(Nat::from(989)));
+    assert_eq!(balance_of_at(bob(), second), Some(Nat::from(10)));
+    assert_eq!(balance_of_at(bob(), third), Some(Nat::from(10)));
+    assert_eq!(balance_of_at(john(), third), Some(Nat::from(1)));
+    assert_eq!(total_supply_at(first), Some(Nat::from(1_000)));
+    assert_eq!(balance_of_at(bob(), third + 1), None);
+    assert_eq!(total_supply_at(third + 1), None);
+    // untouched accounts and unchanged snapshots keep nothing
+    assert_eq!(SNAPSHOTS.with(|s| s.borrow().balances.len()), 3);
+
+    set_max_snapshots(1);
+    assert_eq!(balance_of_at(bob(), first), None);
+    assert_eq!(balance_of_at(bob(), third), Some(Nat::from(10)));
+  }
+
+  #[test]
+  fn migrate_snapshot_copies() {
+    let mut copies = SnapshotCopies::new();
+    let balances = |entries: Vec<(Principal, u64)>| -> Balances {
+      entries
+        .into_iter()
+        .map(|(who, balance)| (who, Nat::from(balance)))
+        .collect()
+    };
+    copies.insert(0, (Nat::from(100), balances(vec![(alice(), 100)])));
+    copies.insert(
+      1,
+      (Nat::from(100), balances(vec![(alice(), 60), (bob(), 40)])),
+    );
+    let current = balances(vec![(alice(), 60), (john(), 40)]);
+    let snapshots = _migrate_snapshots(copies, &current);
+    let at = |id, who| {
+      snapshots.balance_at(
+        id,
+        who,
+        current.get(&who).cloned().unwrap_or_else(|| Nat::from(0)),
+      )
+    };
+    assert_eq!(at(0, alice()), Some(Nat::from(100)));
+    assert_eq!(at(0, bob()), Some(Nat::from(0)));
+    assert_eq!(at(0, john()), Some(Nat::from(0)));
+    assert_eq!(at(1, alice()), Some(Nat::from(60)));
+    assert_eq!(at(1, bob()), Some(Nat::from(40)));
+    assert_eq!(at(1, john()), Some(Nat::from(0)));
+    assert_eq!(snapshots.supplies.len(), 2);
+  }
+
+  #[test]
+  fn icrc3_blocks_are_chained() {
+    setup();
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    block_on(transfer(bob(), Nat::from(20))).unwrap();
+
+    let result = icrc3_get_blocks(vec![GetBlocksArgs {
+      start: Nat::from(0),
+      length: Nat::from(10),
+    }]);
+    assert_eq!(result.log_length, Nat::from(2));
+    assert_eq!(result.blocks.len(), 2);
+    assert_eq!(result.blocks[1].id, Nat::from(1));
+    assert_eq!(field(&result.blocks[0].block, "phash"), None);
+    assert_eq!(
+      field(&result.blocks[1].block, "phash"),
+      Some(icrc3::Value::Blob(icrc3::hash(&result.blocks[0].block)))
+    );
+    let tip_hash = HISTORY.with(|h| h.borrow().tip_hash.clone());
+    assert_eq!(tip_hash, Some(icrc3::hash(&result.blocks[1].block)));
+
+    let tail = icrc3_get_blocks(vec![GetBlocksArgs {
+      start: Nat::from(1),
+      length: Nat::from(10),
+    }]);
+    assert_eq!(tail.blocks.len(), 1);
+    assert_eq!(tail.blocks[0].block, result.blocks[1].block);
+  }
+
+  #[test]
+  fn migrate_unversioned_state() {
+    let stats = StatsDataV0 {
+      logo: "logo".to_string(),
+      name: "Token".to_string(),
+      symbol: "TKN".to_string(),
+      decimals: 8,
+      total_supply: Nat::from(1_000),
+      owner: alice(),
+      fee: Nat::from(1),
+      fee_to: bob(),
+      history_size: 7,
+      deploy_time: 42,
+    };
+    let balances = Balances::from_iter(vec![(alice(), Nat::from(1_000))]);
+    let mut allowances = Allowances::new();
+    allowances.insert(alice(), HashMap::from_iter(vec![(bob(), Nat::from(5))]));
+    let bytes = candid::encode_args((stats, balances, allowances, TxLog::default())).unwrap();
+
+    let (stats, balances, allowances, _): (StatsDataV0, Balances, Allowances, TxLog) =
+      candid::decode_args(&bytes).unwrap();
+    let stats = _migrate_v0(stats);
+    assert_eq!(stats.name, "Token");
+    assert_eq!(stats.decimals, 8);
+    assert_eq!(stats.total_supply, Nat::from(1_000));
+    assert_eq!(stats.owner, alice());
+    assert_eq!(stats.fee_to, bob());
+    assert_eq!(stats.deploy_time, 42);
+    assert_eq!(stats.tx_counter, 7);
+    assert_eq!(stats.max_logo_size, DEFAULT_MAX_LOGO_SIZE);
+    assert!(stats.recovery_enabled);
+    assert_eq!(balances.get(&alice()), Some(&Nat::from(1_000)));
+    assert_eq!(allowances[&alice()][&bob()], Nat::from(5));
+  }
+
+  #[test]
+  fn overdrawing_returns_insufficient_balance() {
+    setup();
+    assert_eq!(
+      _transfer(bob(), alice(), Nat::from(1)),
+      Err(TxError::InsufficientBalance)
+    );
+    // A supply below the burned amount must not trap either.
+    STATS.with(|s| s.borrow_mut().total_supply = Nat::from(5));
+    assert_eq!(
+      _burn(alice(), Nat::from(10)),
+      Err(TxError::InsufficientBalance)
+    );
+    assert_eq!(balance_of(alice()), Nat::from(1_000));
+    assert_eq!(total_supply(), Nat::from(5));
+
+    MockContext::new().with_caller(bob()).inject();
+    assert_eq!(
+      block_on(burn(Nat::from(1))),
+      Err(TxError::InsufficientBalance)
+    );
+    assert_eq!(history_size(), 0);
+  }
+
+  #[test]
+  fn spendable_now_reports_each_restriction() {
+    setup();
+    block_on(transfer(bob(), Nat::from(100))).unwrap();
+    let spendable = spendable_now(bob());
+    assert_eq!(spendable.amount, Nat::from(99));
+    assert_eq!(spendable.reason_if_zero, None);
+    let reason = |account| spendable_now(account).reason_if_zero.unwrap();
+
+    assert_eq!(reason(Principal::from_slice(&[8; 10])), "zero balance");
+    _balance_ins(Principal::from_slice(&[8; 10]), Nat::from(1));
+    assert_eq!(
+      reason(Principal::from_slice(&[8; 10])),
+      "balance does not cover the fee"
+    );
+
+    BLOCKLIST.with(|b| b.borrow_mut().insert(bob()));
+    assert_eq!(reason(bob()), "account blocked");
+    BLOCKLIST.with(|b| b.borrow_mut().remove(&bob()));
+
+    set_sale_window(Some(ic::time() + 1), None);
+    assert_eq!(reason(bob()), "outside sale window");
+    // the owner is not held to the sale window
+    assert_eq!(spendable_now(alice()).amount, Nat::from(898));
+    set_sale_window(None, None);
+
+    STATS.with(|s| s.borrow_mut().rate_limit = Some((1, 1_000)));
+    TRANSFER_TIMES.with(|t| {
+      t.borrow_mut()
+        .insert(bob(), VecDeque::from(vec![ic::time()]))
+    });
+    assert_eq!(reason(bob()), "rate limited");
+    STATS.with(|s| s.borrow_mut().rate_limit = None);
+
+    STATS.with(|s| s.borrow_mut().transfer_cooldown_secs = 60);
+    LAST_TRANSFER.with(|l| l.borrow_mut().insert(bob(), ic::time()));
+    assert_eq!(reason(bob()), "cooldown");
+    STATS.with(|s| s.borrow_mut().transfer_cooldown_secs = 0);
+
+    STATS.with(|s| s.borrow_mut().min_cycles_threshold = u64::MAX);
+    assert_eq!(reason(bob()), "low cycles");
+  }
+
+  #[test]
+  fn approve_stores_the_requested_value() {
+    setup();
+    block_on(approve(bob(), Nat::from(100))).unwrap();
+    assert_eq!(allowance(alice(), bob()), Nat::from(100));
+    assert_eq!(balance_of(alice()), Nat::from(999));
+
+    // transferFrom draws the value and the fee from the approval
+    MockContext::new().with_caller(bob()).inject();
+    assert_eq!(
+      block_on(transfer_from(alice(), john(), Nat::from(100))),
+      Err(TxError::InsufficientAllowance)
+    );
+    block_on(transfer_from(alice(), john(), Nat::from(99))).unwrap();
+    assert_eq!(allowance(alice(), bob()), Nat::from(0));
+    assert_eq!(balance_of(alice()), Nat::from(899));
+  }
+
+  #[test]
+  fn batch_approve_sets_each_allowance() {
+    setup();
+    let receipts = block_on(batch_approve(vec![
+      (bob(), Nat::from(10)),
+      (john(), Nat::from(20)),
+      (Principal::from_slice(&[8; 10]), Nat::from(30)),
+    ]));
+    assert_eq!(receipts.len(), 3);
+    assert!(receipts.iter().all(|receipt| receipt.is_ok()));
+    assert_eq!(allowance(alice(), bob()), Nat::from(10));
+    assert_eq!(allowance(alice(), john()), Nat::from(20));
+    assert_eq!(
+      allowance(alice(), Principal::from_slice(&[8; 10])),
+      Nat::from(30)
+    );
+    // one fee per approval
+    assert_eq!(balance_of(alice()), Nat::from(997));
+
+    MockContext::new().with_caller(bob()).inject();
+    assert_eq!(
+      block_on(batch_approve(vec![(alice(), Nat::from(1))])),
+      vec![Err(TxError::InsufficientBalance)]
+    );
+  }
+
+  #[test]
+  fn burned_fees_lower_the_supply() {
+    setup();
+    set_burn_fees(true);
+    assert!(get_burn_fees());
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    block_on(approve(bob(), Nat::from(50))).unwrap();
+    assert_eq!(total_supply(), Nat::from(998));
+    assert_eq!(balance_of(john()), Nat::from(0));
+    let held = BALANCES.with(|b| {
+      b.borrow()
+        .values()
+        .fold(Nat::from(0), |sum, balance| sum + balance.clone())
+    });
+    assert_eq!(held, total_supply());
+  }
+
+  #[test]
+  fn transfers_only_pass_during_the_sale_window() {
+    setup();
+    block_on(transfer(bob(), Nat::from(100))).unwrap();
+    MockContext::new().with_caller(bob()).inject();
+    block_on(approve(john(), Nat::from(50))).unwrap();
+    let now = ic::time();
+    let outside = Err(TxError::Other("outside sale window".to_string()));
+
+    set_sale_window(Some(now + 10), Some(now + 20));
+    assert_eq!(get_sale_window(), (Some(now + 10), Some(now + 20)));
+    MockContext::new()
+      .with_caller(bob())
+      .with_time(now)
+      .inject();
+    assert_eq!(block_on(transfer(john(), Nat::from(1))), outside);
+    MockContext::new()
+      .with_caller(john())
+      .with_time(now)
+      .inject();
+    assert_eq!(
+      block_on(transfer_from(bob(), alice(), Nat::from(1))),
+      outside
+    );
+
+    MockContext::new()
+      .with_caller(bob())
+      .with_time(now + 10)
+      .inject();
+    block_on(transfer(john(), Nat::from(1))).unwrap();
+
+    MockContext::new()
+      .with_caller(bob())
+      .with_time(now + 20)
+      .inject();
+    assert_eq!(block_on(transfer(john(), Nat::from(1))), outside);
+    // the owner is not held to the window
+    MockContext::new()
+      .with_caller(alice())
+      .with_time(now + 20)
+      .inject();
+    block_on(transfer(bob(), Nat::from(1))).unwrap();
+  }
+
+  #[test]
+  fn history_cache_info_notices_eviction() {
+    setup();
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    block_on(transfer(bob(), Nat::from(20))).unwrap();
+    let info = history_cache_info();
+    assert_eq!(info.cached_count, 2);
+    assert_eq!(info.oldest_index, Nat::from(0));
+    assert!(info.complete);
+
+    HISTORY.with(|h| h.borrow_mut().evict());
+    let info = history_cache_info();
+    assert_eq!(info.cached_count, 1);
+    assert_eq!(info.oldest_index, Nat::from(1));
+    assert!(!info.complete);
+  }
+
+  #[test]
+  fn reemit_record_sends_the_record_to_cap_again() {
+    setup();
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    let record = HISTORY.with(|h| h.borrow().records[0].clone());
+    MockContext::new()
+      .with_caller(alice())
+      .with_handler(Method::new().name("insert").response(Nat::from(42)))
+      .inject();
+    assert_eq!(block_on(reemit_record(record)), Ok(Nat::from(42)));
+    assert_eq!(HISTORY.with(|h| h.borrow().records.len()), 1);
+    assert_eq!(balance_of(bob()), Nat::from(10));
+  }
+
+  #[test]
+  fn tip_certificate_follows_the_latest_block() {
+    setup();
+    assert!(icrc3_get_tip_certificate().is_none());
+    let contains = |tree: &[u8], hash: &[u8]| tree.windows(hash.len()).any(|w| w == hash);
+
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    MockContext::new()
+      .with_caller(alice())
+      .with_data_certificate(vec![1, 2, 3])
+      .inject();
+    let first_tip = HISTORY.with(|h| h.borrow().tip_hash.clone()).unwrap();
+    let first = icrc3_get_tip_certificate().unwrap();
+    assert_eq!(first.certificate, vec![1, 2, 3]);
+    assert!(contains(&first.hash_tree, &first_tip));
+
+    block_on(transfer(bob(), Nat::from(20))).unwrap();
+    let second_tip = HISTORY.with(|h| h.borrow().tip_hash.clone()).unwrap();
+    assert_ne!(first_tip, second_tip);
+    let second = icrc3_get_tip_certificate().unwrap();
+    assert!(contains(&second.hash_tree, &second_tip));
+    assert!(!contains(&second.hash_tree, &first_tip));
+  }
+
+  #[test]
+  fn transfer_debit_matches_the_transfer() {
+    setup();
+    set_fee(Nat::from(3));
+    let debit = |from, value| transfer_debit(from, john(), Nat::from(value)).unwrap();
+    assert_eq!(debit(alice(), 10), Nat::from(13));
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    assert_eq!(balance_of(alice()), Nat::from(987));
+
+    set_burn_fees(true);
+    assert_eq!(debit(alice(), 10), Nat::from(13));
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    assert_eq!(balance_of(alice()), Nat::from(974));
+
+    add_fee_exempt(alice());
+    assert_eq!(debit(alice(), 10), Nat::from(10));
+    assert_eq!(debit(bob(), 10), Nat::from(13));
+    assert_eq!(
+      transfer_debit(bob(), john(), Nat::from(100)),
+      Err(TxError::InsufficientBalance)
+    );
+
+    set_fee_token(Some(Principal::from_slice(&[9; 10])), Nat::from(5));
+    assert_eq!(debit(bob(), 10), Nat::from(10));
+  }
+
+  #[test]
+  fn transfer_debit_of_a_self_transfer_is_the_fee() {
+    setup();
+    set_fee(Nat::from(3));
+    assert_eq!(
+      transfer_debit(alice(), alice(), Nat::from(500)),
+      Ok(Nat::from(3))
+    );
+    block_on(transfer(alice(), Nat::from(500))).unwrap();
+    assert_eq!(balance_of(alice()), Nat::from(997));
+  }
+
+  #[test]
+  fn transfer_debit_to_a_burn_sink_counts_the_burn() {
+    setup();
+    set_fee(Nat::from(3));
+    let sink = Principal::from_slice(&[6; 29]);
+    set_burn_sinks(vec![sink]);
+    assert_eq!(
+      transfer_debit(alice(), sink, Nat::from(10)),
+      Ok(Nat::from(13))
+    );
+    block_on(transfer(sink, Nat::from(10))).unwrap();
+    assert_eq!(balance_of(alice()), Nat::from(987));
+    assert_eq!(balance_of(sink), Nat::from(0));
+    assert_eq!(total_supply(), Nat::from(990));
+  }
+
+  #[test]
+  fn get_holders_pages_equal_balances_exactly_once() {
+    setup();
+    BALANCES.with(|b| b.borrow_mut().clear());
+    for i in 1..=50u8 {
+      _balance_ins(Principal::from_slice(&[i; 10]), Nat::from(100));
+    }
+    let mut pages = get_holders(0, 10);
+    pages.extend(get_holders(10, 40));
+    let mut all: Vec<(Principal, Nat)> = (1..=50u8)
+      .map(|i| (Principal::from_slice(&[i; 10]), Nat::from(100)))
+      .collect();
+    all.sort_by(|a, b| a.0.as_slice().cmp(b.0.as_slice()));
+    assert_eq!(pages, all);
+    assert_eq!(get_holders(0, 50), all);
+  }
+
+  #[test]
+  fn approvals_below_the_minimum_are_rejected() {
+    setup();
+    set_min_approval(Nat::from(10));
+    assert_eq!(get_min_approval(), Nat::from(10));
+    assert_eq!(
+      block_on(approve(bob(), Nat::from(9))),
+      Err(TxError::AmountTooSmall)
+    );
+    assert_eq!(allowance(alice(), bob()), Nat::from(0));
+    block_on(approve(bob(), Nat::from(10))).unwrap();
+    assert_eq!(allowance(alice(), bob()), Nat::from(10));
+    // revoking is never dust
+    block_on(approve(bob(), Nat::from(0))).unwrap();
+    assert_eq!(allowance(alice(), bob()), Nat::from(0));
+  }
+
+  #[test]
+  fn allowance_edges_page_over_the_whole_graph() {
+    setup();
+    block_on(transfer(bob(), Nat::from(100))).unwrap();
+    block_on(approve(bob(), Nat::from(10))).unwrap();
+    block_on(approve(john(), Nat::from(20))).unwrap();
+    MockContext::new().with_caller(bob()).inject();
+    block_on(approve(alice(), Nat::from(30))).unwrap();
+    block_on(approve(john(), Nat::from(40))).unwrap();
+
+    let edges = |start, limit| -> Vec<(Principal, Principal, Nat)> {
+      allowance_edges(start, limit)
+        .into_iter()
+        .map(|edge| (edge.owner, edge.spender, edge.value))
+        .collect()
+    };
+    let mut pages = edges(0, 3);
+    assert_eq!(pages.len(), 3);
+    pages.extend(edges(3, 3));
+    assert_eq!(
+      pages,
+      vec![
+        (alice(), bob(), Nat::from(10)),
+        (alice(), john(), Nat::from(20)),
+        (bob(), alice(), Nat::from(30)),
+        (bob(), john(), Nat::from(40)),
+      ]
+    );
+    assert!(edges(4, 3).is_empty());
+  }
+
+  #[test]
+  fn chunked_layout_roundtrips_large_maps() {
+    let balances: Vec<(Principal, Nat)> = (0..100_000u32)
+      .map(|i| (Principal::from_slice(&i.to_be_bytes()), Nat::from(i)))
+      .collect();
+    let allows: Vec<(Principal, Principal, Nat)> = balances
+      .iter()
+      .take(25_000)
+      .map(|(owner, value)| (*owner, alice(), value.clone()))
+      .collect();
+    let mut bytes = Vec::new();
+    stable::save_to(
+      &mut bytes,
+      DATA_VERSION,
+      ("Token".to_string(),),
+      &balances,
+      &allows,
+    );
+
+    let ((name,), restored_balances, restored_allows) =
+      stable::restore_from::<_, (String,), (Principal, Nat), (Principal, Principal, Nat)>(
+        &mut bytes.as_slice(),
+      );
+    assert_eq!(name, "Token");
+    assert_eq!(restored_balances, balances);
+    assert_eq!(restored_allows, allows);
+  }
+
+  #[test]
+  fn upgrade_repairs_inconsistent_state() {
+    setup();
+    BALANCES.with(|b| b.borrow_mut().insert(bob(), Nat::from(0)));
+    ALLOWS.with(|a| {
+      a.borrow_mut()
+        .insert(alice(), HashMap::from_iter(vec![(bob(), Nat::from(0))]))
+    });
+    STATS.with(|s| {
+      let mut stats = s.borrow_mut();
+      stats.total_supply = Nat::from(1_500);
+      stats.repair_on_upgrade = true;
+    });
+    let mut bytes = Vec::new();
+    _save_state(&mut bytes);
+
+    let (state, extras) = _restore_chunked(&mut bytes.as_slice());
+    _load_state(state, extras);
+    assert!(BALANCES.with(|b| !b.borrow().contains_key(&bob())));
+    assert!(ALLOWS.with(|a| a.borrow().is_empty()));
+    assert_eq!(total_supply(), Nat::from(1_000));
+    assert_eq!(balance_of(alice()), Nat::from(1_000));
+    assert!(!STATS.with(|s| s.borrow().repair_on_upgrade));
+  }
+
+  #[test]
+  fn wiring_matches_the_configuration() {
+    let canister = Principal::from_slice(&[6; 10]);
+    MockContext::new()
+      .with_caller(alice())
+      .with_id(canister)
+      .inject();
+    let cap = Principal::from_slice(&[7; 10]);
+    init(
+      "logo".to_string(),
+      "Token".to_string(),
+      "TKN".to_string(),
+      8,
+      Nat::from(1_000),
+      alice(),
+      Nat::from(1),
+      john(),
+      cap,
+      None,
+      None,
+    );
+    let wiring = get_wiring();
+    assert_eq!(wiring.canister, canister);
+    assert_eq!(wiring.cap, Some(cap));
+    assert_eq!(wiring.fee_token, None);
+
+    set_fee_token(Some(Principal::from_slice(&[9; 10])), Nat::from(5));
+    assert_eq!(
+      get_wiring().fee_token,
+      Some(Principal::from_slice(&[9; 10]))
+    );
+  }
+
+  #[test]
+  fn compact_balances_drops_zero_entries() {
+    setup();
+    BALANCES.with(|b| {
+      let mut balances = b.borrow_mut();
+      balances.insert(bob(), Nat::from(0));
+      balances.insert(john(), Nat::from(0));
+    });
+    assert_eq!(compact_balances(), 2);
+    assert!(BALANCES.with(|b| !b.borrow().contains_key(&bob())));
+    assert_eq!(balance_of(alice()), Nat::from(1_000));
+    assert_eq!(compact_balances(), 0);
+  }
+
+  #[test]
+  fn self_transfer_only_costs_the_fee() {
+    setup();
+    block_on(transfer(alice(), Nat::from(999))).unwrap();
+    assert_eq!(balance_of(alice()), Nat::from(999));
+    assert_eq!(balance_of(john()), Nat::from(1));
+    assert_eq!(total_supply(), Nat::from(1_000));
+
+    block_on(approve(bob(), Nat::from(100))).unwrap();
+    MockContext::new().with_caller(bob()).inject();
+    block_on(transfer_from(alice(), alice(), Nat::from(500))).unwrap();
+    assert_eq!(balance_of(alice()), Nat::from(997));
+    // only the fee is taken from the allowance
+    assert_eq!(allowance(alice(), bob()), Nat::from(99));
+  }
+
+  fn init_token(decimals: u8, name: &str, symbol: &str, fee: u64, fee_to: Principal) {
+    MockContext::new().with_caller(alice()).inject();
+    init(
+      "logo".to_string(),
+      name.to_string(),
+      symbol.to_string(),
+      decimals,
+      Nat::from(1_000),
+      alice(),
+      Nat::from(fee),
+      fee_to,
+      Principal::from_slice(&[7; 10]),
+      None,
+      None,
+    );
+  }
+
+  #[test]
+  #[should_panic(expected = "decimals must be at most 18")]
+  fn init_rejects_too_many_decimals() {
+    init_token(19, "Token", "TKN", 1, john());
+  }
+
+  #[test]
+  #[should_panic(expected = "name must not be empty")]
+  fn init_rejects_an_empty_name() {
+    init_token(8, "", "TKN", 1, john());
+  }
+
+  #[test]
+  #[should_panic(expected = "symbol must not be empty")]
+  fn init_rejects_an_empty_symbol() {
+    init_token(8, "Token", "", 1, john());
+  }
+
+  #[test]
+  #[should_panic(expected = "fee_to must not be anonymous when there is a fee")]
+  fn init_rejects_an_anonymous_fee_to() {
+    init_token(8, "Token", "TKN", 1, Principal::anonymous());
+  }
+
+  #[test]
+  fn init_accepts_an_anonymous_fee_to_without_a_fee() {
+    init_token(18, "Token", "TKN", 0, Principal::anonymous());
+    assert_eq!(decimals(), 18);
+  }
+
+  #[test]
+  fn transfers_to_anonymous_follow_the_flag() {
+    setup();
+    block_on(transfer(Principal::anonymous(), Nat::from(10))).unwrap();
+    assert_eq!(balance_of(Principal::anonymous()), Nat::from(10));
+
+    set_reject_anonymous(true);
+    assert_eq!(
+      block_on(transfer(Principal::anonymous(), Nat::from(10))),
+      Err(TxError::ErrorTo)
+    );
+    assert_eq!(
+      block_on(approve(Principal::anonymous(), Nat::from(10))),
+      Err(TxError::ErrorTo)
+    );
+    assert_eq!(
+      block_on(mint(Principal::anonymous(), Nat::from(10))),
+      Err(TxError::ErrorTo)
+    );
+    assert_eq!(balance_of(Principal::anonymous()), Nat::from(10));
+  }
+
+  #[test]
+  fn zero_transfers_are_rejected_unless_allowed() {
+    setup();
+    assert_eq!(
+      block_on(transfer(bob(), Nat::from(0))),
+      Err(TxError::AmountTooSmall)
+    );
+    assert_eq!(history_size(), 0);
+    assert_eq!(balance_of(john()), Nat::from(0));
+
+    block_on(approve(bob(), Nat::from(10))).unwrap();
+    block_on(approve(bob(), Nat::from(0))).unwrap();
+    assert_eq!(allowance(alice(), bob()), Nat::from(0));
+
+    set_allow_zero_transfers(true);
+    block_on(transfer(bob(), Nat::from(0))).unwrap();
+    assert_eq!(balance_of(alice()), Nat::from(997));
+  }
+
+  #[test]
+  fn rate_limit_counts_transfers_per_account() {
+    setup();
+    block_on(transfer(bob(), Nat::from(100))).unwrap();
+    block_on(transfer(john(), Nat::from(100))).unwrap();
+    STATS.with(|s| s.borrow_mut().rate_limit = Some((1, 1_000)));
+    assert_eq!(get_rate_limit(), Some((1, 1_000)));
+    let now = ic::time();
+    let limited = Err(TxError::Other("rate limited".to_string()));
+
+    MockContext::new()
+      .with_caller(bob())
+      .with_time(now)
+      .inject();
+    block_on(transfer(alice(), Nat::from(1))).unwrap();
+    assert_eq!(block_on(transfer(alice(), Nat::from(1))), limited);
+    // other accounts and the owner keep their own budget
+    MockContext::new()
+      .with_caller(john())
+      .with_time(now)
+      .inject();
+    block_on(transfer(alice(), Nat::from(1))).unwrap();
+    MockContext::new()
+      .with_caller(alice())
+      .with_time(now)
+      .inject();
+    block_on(transfer(bob(), Nat::from(1))).unwrap();
+    block_on(transfer(bob(), Nat::from(1))).unwrap();
+
+    MockContext::new()
+      .with_caller(bob())
+      .with_time(now + 1_000)
+      .inject();
+    block_on(transfer(alice(), Nat::from(1))).unwrap();
+  }
+
+  #[test]
+  fn transfer_from_signed_accepts_a_client_built_message() {
+    use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+    setup();
+    let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+    let public = PublicKey::from(&secret);
+    let keypair = Keypair { secret, public };
+    let mut der = vec![
+      0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+    ];
+    der.extend_from_slice(public.as_bytes());
+    let from = Principal::self_authenticating(&der);
+    _balance_ins(from, Nat::from(100));
+
+    let domain = "dip20-transfer-from-signed";
+    let mut message = vec![domain.len() as u8];
+    message.extend_from_slice(domain.as_bytes());
+    message.extend(candid::encode_args((ic::id(), bob(), Nat::from(10), 0u64)).unwrap());
+    let signature = keypair.sign(&message);
+
+    MockContext::new().with_caller(john()).inject();
+    block_on(transfer_from_signed(
+      from,
+      bob(),
+      Nat::from(10),
+      0,
+      public.as_bytes().to_vec(),
+      signature.to_bytes().to_vec(),
+    ))
+    .unwrap();
+    assert_eq!(balance_of(bob()), Nat::from(10));
+    assert_eq!(balance_of(from), Nat::from(89));
+  }
+
+  // The insert future stands in for a transfer suspended on its CAP insert.
+  fn suspended_transfer() -> impl Future<Output = TxReceipt> {
+    setup();
+    STATS.with(|s| s.borrow_mut().cap_batch_size = Some(0));
+    MockContext::new()
+      .with_caller(alice())
+      .with_handler(Method::new().name("insert").response(Nat::from(1)))
+      .inject();
+    _mutate(|| _send(alice(), bob(), Nat::from(10))).unwrap()
+  }
+
+  #[test]
+  #[should_panic(expected = "reentrant state mutation")]
+  fn calls_are_rejected_while_the_callers_record_is_pending() {
+    let _first = suspended_transfer();
+    let _ = block_on(transfer(bob(), Nat::from(10)));
+  }
+
+  #[test]
+  fn other_callers_and_later_calls_are_not_held_up_by_a_pending_record() {
+    let first = suspended_transfer();
+    MockContext::new()
+      .with_caller(bob())
+      .with_handler(Method::new().name("insert").response(Nat::from(2)))
+      .inject();
+    block_on(transfer(john(), Nat::from(5))).unwrap();
+
+    MockContext::new()
+      .with_caller(alice())
+      .with_handler(Method::new().name("insert").response(Nat::from(3)))
+      .inject();
+    assert_eq!(block_on(first), Ok(Nat::from(0)));
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    assert_eq!(balance_of(bob()), Nat::from(14));
+  }
+
+  #[test]
+  fn heartbeat_skips_a_running_flush_but_not_a_stale_one() {
+    setup();
+    MockContext::new()
+      .with_caller(alice())
+      .with_time(CAP_FLUSH_STALE_SECS * 2_000_000_000)
+      .with_handler(Method::new().name("insert").response(Nat::from(1)))
+      .inject();
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+    CAP_FLUSHING.with(|f| f.set(true));
+    CAP_LAST_FLUSH.with(|l| l.set(ic::time() - 1_000_000_000));
+    block_on(heartbeat());
+    assert_eq!(get_cap_queue_size(), 1);
+
+    // left over from a flush whose callback trapped
+    CAP_LAST_FLUSH.with(|l| l.set(ic::time() - CAP_FLUSH_STALE_SECS * 1_000_000_000));
+    block_on(heartbeat());
+    assert_eq!(get_cap_queue_size(), 0);
+    assert!(!CAP_FLUSHING.with(|f| f.get()));
+  }
+
+  #[test]
+  fn icrc3_get_blocks_caps_the_response() {
+    setup();
+    for _ in 0..MAX_BLOCKS_PER_RESPONSE + 5 {
+      _add_record_detached(
+        alice(),
+        Operation::Mint,
+        alice(),
+        bob(),
+        Nat::from(1),
+        Nat::from(0),
+        ic::time(),
+        TransactionStatus::Succeeded,
+      );
+    }
+    let result = icrc3_get_blocks(vec![
+      GetBlocksArgs {
+        start: Nat::from(0),
+        length: Nat::from(MAX_BLOCKS_PER_RESPONSE + 5),
+      },
+      GetBlocksArgs {
+        start: Nat::from(0),
+        length: Nat::from(1),
+      },
+    ]);
+    assert_eq!(result.log_length, Nat::from(MAX_BLOCKS_PER_RESPONSE + 5));
+    assert_eq!(result.blocks.len(), MAX_BLOCKS_PER_RESPONSE);
+    assert_eq!(
+      result.blocks.last().unwrap().id,
+      Nat::from(MAX_BLOCKS_PER_RESPONSE - 1)
+    );
+
+    let rest = icrc3_get_blocks(vec![GetBlocksArgs {
+      start: Nat::from(MAX_BLOCKS_PER_RESPONSE),
+      length: Nat::from(MAX_BLOCKS_PER_RESPONSE),
+    }]);
+    assert_eq!(rest.blocks.len(), 5);
+    assert!(icrc3_get_archives(GetArchivesArgs { from: None }).is_empty());
+  }
+
+  fn listener_notified_with(args: (Principal, Principal, Nat, Operation)) {
+    setup();
+    let listener = Principal::from_slice(&[7; 10]);
+    register_transfer_listener(listener, "onTransfer".to_string()).unwrap();
+    MockContext::new()
+      .with_caller(alice())
+      .with_handler(
+        Method::new()
+          .name("onTransfer")
+          .expect_arguments(args)
+          .response(()),
+      )
+      .inject();
+    block_on(transfer(bob(), Nat::from(10))).unwrap();
+  }
+
+  #[test]
+  fn registered_listeners_receive_transfers() {
+    listener_notified_with((alice(), bob(), Nat::from(10), Operation::Transfer));
+  }
+
+  // Only a call that reached the listener can fail on its arguments.
+  #[test]
+  #[should_panic(expected = "unexpected arguments")]
+  fn registered_listeners_are_called() {
+    listener_notified_with((alice(), bob(), Nat::from(11), Operation::Transfer));
+  }
+
+  #[test]
+  fn transfer_from_returning_allowance_reports_what_is_left() {
+    setup();
+    block_on(approve(bob(), Nat::from(100))).unwrap();
+    MockContext::new().with_caller(bob()).inject();
+    let (_, remaining) = block_on(transfer_from_returning_allowance(
+      alice(),
+      john(),
+      Nat::from(10),
+    ))
+    .unwrap();
+    // value plus the fee of 1
+    assert_eq!(remaining, Nat::from(89));
+    assert_eq!(remaining, allowance(alice(), bob()));
+
+    assert_eq!(
+      block_on(transfer_from_returning_allowance(
+        alice(),
+        john(),
+        Nat::from(89)
+      )),
+      Err(TxError::InsufficientAllowance)
+    );
+    assert_eq!(allowance(alice(), bob()), Nat::from(89));
+  }
+
+  #[test]
+  fn minter_stats_keep_each_minters_total() {
+    setup();
+    block_on(mint(bob(), Nat::from(10))).unwrap();
+    MockContext::new().with_caller(bob()).inject();
+    block_on(mint(john(), Nat::from(5))).unwrap();
+    MockContext::new().with_caller(alice()).inject();
+    block_on(mint(john(), Nat::from(3))).unwrap();
+
+    let mut expected = vec![(alice(), Nat::from(13)), (bob(), Nat::from(5))];
+    expected.sort_by_key(|a| a.0);
+    assert_eq!(get_minter_stats(), expected);
+  }
+
+  #[test]
+  fn user_approvals_are_ordered_by_spender() {
+    setup();
+    let carol = Principal::from_slice(&[5; 29]);
+    block_on(approve(john(), Nat::from(3))).unwrap();
+    block_on(approve(carol, Nat::from(5))).unwrap();
+    block_on(approve(bob(), Nat::from(2))).unwrap();
+
+    let approvals = get_user_approvals(alice());
+    assert_eq!(
+      approvals,
+      vec![
+        (bob(), Nat::from(2)),
+        (john(), Nat::from(3)),
+        (carol, Nat::from(5)),
+      ]
+    );
+    assert_eq!(get_user_approvals(alice()), approvals);
+  }
+
+  #[test]
+  fn zero_balances_are_not_holders() {
+    setup();
+    block_on(mint(bob(), Nat::from(0))).unwrap();
+    // slipped past `_balance_ins`
+    BALANCES.with(|b| b.borrow_mut().insert(john(), Nat::from(0)));
+
+    assert_eq!(get_holders(0, 10), vec![(alice(), Nat::from(1_000))]);
+    assert_eq!(get_holders_count(), 1);
+    assert_eq!(get_token_info().holderNumber, 1);
+  }
+}
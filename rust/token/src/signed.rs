@@ -0,0 +1,50 @@
+/**
+* Module     : signed.rs
+* Copyright  : 2022 Fleek
+* License    : GPL 3.0
+* Maintainer : Psychedelic <support@fleek.co>
+* Stability  : Experimental
+*/
+use candid::{encode_args, Nat};
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+use ic_kit::Principal;
+use std::convert::TryFrom;
+
+const DOMAIN: &[u8] = b"dip20-transfer-from-signed";
+const ED25519_DER_PREFIX: [u8; 12] = [
+  0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+// What `from` signs to let a relayer move `value` to `to`:
+//
+//   len(DOMAIN) | DOMAIN | candid(token, to, value, nonce)
+//
+// where `len(DOMAIN)` is a single byte (26) and the candid part encodes the
+// arguments as `(principal, principal, nat, nat64)`. The token's own id is
+// part of it so the signature can't be replayed on another token.
+pub fn message(token: Principal, to: Principal, value: &Nat, nonce: u64) -> Vec<u8> {
+  let mut message = vec![DOMAIN.len() as u8];
+  message.extend_from_slice(DOMAIN);
+  message.extend(encode_args((token, to, value, nonce)).unwrap());
+  message
+}
+
+// Checks that `public_key` is the Ed25519 key `from` is derived from and
+// that it signed `message`.
+pub fn verify(
+  from: Principal,
+  public_key: &[u8],
+  message: &[u8],
+  signature: &[u8],
+) -> Result<(), String> {
+  let mut der = ED25519_DER_PREFIX.to_vec();
+  der.extend_from_slice(public_key);
+  if Principal::self_authenticating(&der) != from {
+    return Err("public key does not belong to from".to_string());
+  }
+  let key = PublicKey::from_bytes(public_key).map_err(|_| "invalid public key".to_string())?;
+  let signature = Signature::try_from(signature).map_err(|_| "invalid signature".to_string())?;
+  key
+    .verify(message, &signature)
+    .map_err(|_| "signature verification failed".to_string())
+}
@@ -0,0 +1,114 @@
+/**
+* Module     : stable.rs
+* Copyright  : 2022 Fleek
+* License    : GPL 3.0
+* Maintainer : Psychedelic <support@fleek.co>
+* Stability  : Experimental
+*/
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, decode_one, encode_args, encode_one, CandidType, Deserialize};
+use std::io::{Read, Write};
+
+// Layout written by `save_to` and read back by `restore_from`, to and from
+// stable memory on upgrades:
+//
+//   VERSIONED_MAGIC | version | blob(candid(values)) | chunked(first) | chunked(second)
+//
+// where `version` is a little endian u32, `blob` a little endian u64 length
+// followed by the bytes and `chunked` a u64 chunk count followed by one blob
+// per chunk of at most `CHUNK_SIZE` items. Older canisters wrote `MAGIC`
+// without a version, and before that `ic::stable_store` output starting
+// with the candid magic, see `version`.
+const MAGIC: &[u8; 8] = b"DIP20STB";
+const VERSIONED_MAGIC: &[u8; 8] = b"DIP20STV";
+const CHUNK_SIZE: usize = 10_000;
+
+pub fn save_to<W, V, A, B>(writer: &mut W, version: u32, values: V, first: &[A], second: &[B])
+where
+  W: Write,
+  V: ArgumentEncoder,
+  A: CandidType,
+  B: CandidType,
+{
+  writer.write_all(VERSIONED_MAGIC).unwrap();
+  writer.write_all(&version.to_le_bytes()).unwrap();
+  write_blob(writer, &encode_args(values).unwrap());
+  write_chunked(writer, first);
+  write_chunked(writer, second);
+}
+
+pub fn restore_from<R, V, A, B>(reader: &mut R) -> (V, Vec<A>, Vec<B>)
+where
+  R: Read,
+  V: for<'de> ArgumentDecoder<'de>,
+  A: CandidType + for<'de> Deserialize<'de>,
+  B: CandidType + for<'de> Deserialize<'de>,
+{
+  let mut magic = [0; 8];
+  reader.read_exact(&mut magic).unwrap();
+  if &magic == VERSIONED_MAGIC {
+    reader.read_exact(&mut [0; 4]).unwrap();
+  } else {
+    assert!(&magic == MAGIC, "stable memory is not chunked");
+  }
+  let values = decode_args(&read_blob(reader)).unwrap();
+  let first = read_chunked(reader);
+  let second = read_chunked(reader);
+  (values, first, second)
+}
+
+// Layout version of the stable memory: 0 for `ic::stable_store` output, 1
+// for the chunked layout without a version and the stored version after
+// that.
+pub fn version() -> u32 {
+  let mut magic = [0; 8];
+  ic_cdk::api::stable::stable_read(0, &mut magic);
+  if &magic == MAGIC {
+    return 1;
+  }
+  if &magic != VERSIONED_MAGIC {
+    return 0;
+  }
+  let mut version = [0; 4];
+  ic_cdk::api::stable::stable_read(8, &mut version);
+  u32::from_le_bytes(version)
+}
+
+fn write_blob<W: Write>(writer: &mut W, bytes: &[u8]) {
+  writer
+    .write_all(&(bytes.len() as u64).to_le_bytes())
+    .unwrap();
+  writer.write_all(bytes).unwrap();
+}
+
+fn read_blob<R: Read>(reader: &mut R) -> Vec<u8> {
+  let mut len = [0; 8];
+  reader.read_exact(&mut len).unwrap();
+  let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+  reader.read_exact(&mut bytes).unwrap();
+  bytes
+}
+
+fn write_chunked<W: Write, T: CandidType>(writer: &mut W, items: &[T]) {
+  let chunks = items.chunks(CHUNK_SIZE);
+  writer
+    .write_all(&(chunks.len() as u64).to_le_bytes())
+    .unwrap();
+  for chunk in chunks {
+    write_blob(writer, &encode_one(chunk).unwrap());
+  }
+}
+
+fn read_chunked<R, T>(reader: &mut R) -> Vec<T>
+where
+  R: Read,
+  T: CandidType + for<'de> Deserialize<'de>,
+{
+  let mut count = [0; 8];
+  reader.read_exact(&mut count).unwrap();
+  let mut items = Vec::new();
+  for _ in 0..u64::from_le_bytes(count) {
+    items.extend(decode_one::<Vec<T>>(&read_blob(reader)).unwrap());
+  }
+  items
+}